@@ -0,0 +1,49 @@
+//! Hashes per second of the midstate path (`Hash::prepare` then `PreparedJob::sha256d`)
+//! against hashing the whole `challenge || nonce` preimage per attempt, as the miner did
+//! before. Run with `cargo bench --bench midstate`.
+
+use pow20miner::Hash;
+use std::{
+    hint::black_box,
+    time::{Duration, Instant},
+};
+
+const HASHES: u64 = 2_000_000;
+
+fn rate(hash: impl FnMut(u64) -> [u8; 32]) -> f64 {
+    let mut hash = hash;
+    let start = Instant::now();
+    for nonce in 0..HASHES {
+        black_box(hash(black_box(nonce)));
+    }
+    HASHES as f64 / start.elapsed().max(Duration::from_nanos(1)).as_secs_f64()
+}
+
+fn main() {
+    println!(
+        "{:>10} {:>14} {:>14} {:>8}",
+        "challenge", "preimage H/s", "midstate H/s", "speedup"
+    );
+
+    for challenge_len in [32, 56, 64, 120] {
+        let challenge = (0..challenge_len).map(|i| i as u8).collect::<Vec<_>>();
+
+        let mut preimage = challenge.clone();
+        preimage.extend_from_slice(&[0; 8]);
+        let preimage_rate = rate(|nonce| {
+            preimage[challenge_len..].copy_from_slice(&nonce.to_be_bytes());
+            Hash::sha256d(&preimage)
+        });
+
+        let job = Hash::prepare(&challenge);
+        let midstate_rate = rate(|nonce| job.sha256d(&nonce.to_be_bytes()));
+
+        println!(
+            "{:>10} {:>14.0} {:>14.0} {:>7.2}x",
+            challenge_len,
+            preimage_rate,
+            midstate_rate,
+            midstate_rate / preimage_rate
+        );
+    }
+}
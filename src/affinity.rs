@@ -0,0 +1,69 @@
+use super::*;
+
+/// Parses a CPU list like `0-3,8,10-11` into CPU ids, in the order given.
+pub fn parse_cpu_list(list: &str) -> Result<Vec<usize>> {
+    let mut cpus = vec![];
+
+    for part in list
+        .split(',')
+        .map(str::trim)
+        .filter(|part| !part.is_empty())
+    {
+        let (first, last) = match part.split_once('-') {
+            Some((first, last)) => (
+                first.trim().parse::<usize>()?,
+                last.trim().parse::<usize>()?,
+            ),
+            None => {
+                let cpu = part.parse::<usize>()?;
+                (cpu, cpu)
+            }
+        };
+
+        if first > last {
+            anyhow::bail!("invalid CPU range {:?}", part);
+        }
+        cpus.extend(first..=last);
+    }
+
+    if cpus.is_empty() {
+        anyhow::bail!("empty CPU list {:?}", list);
+    }
+
+    Ok(cpus)
+}
+
+/// Pins the calling thread to `cpu`.
+pub fn pin_current_thread(cpu: usize) -> Result<()> {
+    let core_ids = core_affinity::get_core_ids().unwrap_or_default();
+    if !core_ids.iter().any(|core_id| core_id.id == cpu) {
+        anyhow::bail!("CPU {} is not available to this process", cpu);
+    }
+
+    if !core_affinity::set_for_current(core_affinity::CoreId { id: cpu }) {
+        anyhow::bail!("failed to pin thread to CPU {}", cpu);
+    }
+
+    Ok(())
+}
+
+/// Raises the nice value of the calling thread to `nice` (1 to 19), so that other
+/// workloads on the box get the CPU first.
+#[cfg(target_os = "linux")]
+pub fn lower_current_thread_priority(nice: i32) -> Result<()> {
+    // on Linux the nice value is per thread, addressed by its thread id
+    let res = unsafe {
+        let tid = libc::syscall(libc::SYS_gettid) as libc::id_t;
+        libc::setpriority(libc::PRIO_PROCESS, tid, nice)
+    };
+    if res != 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn lower_current_thread_priority(_nice: i32) -> Result<()> {
+    anyhow::bail!("lowering the priority of hashing threads is only supported on Linux")
+}
@@ -1,4 +1,5 @@
 use super::*;
+use std::time::Duration;
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct Ticker {
@@ -10,65 +11,446 @@ pub struct Ticker {
     pub id: String,
 }
 
+/// The last ticker response and its validators, so the next fetch can be conditional.
+#[derive(Debug, Clone, Default)]
+pub struct CachedTicker {
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+    pub ticker: Option<Ticker>,
+}
+
+/// A failed response from the API: its status and what the body says about it.
+#[derive(Debug, Clone)]
+pub struct ServerError {
+    pub status: u16,
+    /// The `message` or `error` field of a JSON body, else the body itself.
+    pub message: String,
+    pub body: String,
+}
+
+impl ServerError {
+    fn parse(status: u16, body: String) -> ServerError {
+        let message = serde_json::from_str::<Value>(&body)
+            .ok()
+            .and_then(|json| {
+                ["message", "error", "msg"]
+                    .iter()
+                    .find_map(|key| json.get(key)?.as_str().map(str::to_string))
+            })
+            .unwrap_or_else(|| body.trim().to_string());
+
+        ServerError {
+            status,
+            message,
+            body,
+        }
+    }
+}
+
+impl std::fmt::Display for ServerError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{} {:?}", self.status, self.message)
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ApiError {
+    #[error("network error: {0}")]
+    Network(#[source] reqwest::Error),
+    #[error("request timed out: {0}")]
+    Timeout(#[source] reqwest::Error),
+    #[error("rate limited: {error}")]
+    RateLimited {
+        error: ServerError,
+        /// From the `Retry-After` header, when the server sent one in seconds.
+        retry_after: Option<Duration>,
+    },
+    /// The share was for a challenge or location that is no longer current.
+    #[error("stale challenge: {0}")]
+    StaleChallenge(ServerError),
+    /// The nonce doesn't solve the challenge.
+    #[error("invalid nonce: {0}")]
+    InvalidNonce(ServerError),
+    /// The share was already submitted.
+    #[error("duplicate share: {0}")]
+    Duplicate(ServerError),
+    /// Any other failed status.
+    #[error("HTTP {0}")]
+    Status(ServerError),
+    #[error("unexpected response: {error}: {body:?}")]
+    InvalidResponse {
+        #[source]
+        error: serde_json::Error,
+        body: String,
+    },
+}
+
+impl ApiError {
+    fn from_response(status: u16, body: String, retry_after: Option<&str>) -> ApiError {
+        let error = ServerError::parse(status, body);
+        if status == 429 {
+            return ApiError::RateLimited {
+                error,
+                retry_after: retry_after
+                    .and_then(|secs| secs.trim().parse().ok())
+                    .map(Duration::from_secs),
+            };
+        }
+
+        // the API has no error codes, so the kind of a rejection is read off its status
+        // and message. Only client errors are rejections, anything else stays a status
+        // so that server errors are retried whatever they say
+        if !(400..500).contains(&status) {
+            return ApiError::Status(error);
+        }
+
+        let message = error.message.to_lowercase();
+        let mentions = |words: &[&str]| words.iter().any(|word| message.contains(word));
+        if status == 409 || mentions(&["duplicate", "already"]) {
+            ApiError::Duplicate(error)
+        } else if status == 410 {
+            ApiError::StaleChallenge(error)
+        } else if mentions(&["nonce", "hash", "difficulty"]) {
+            // before the challenge wording, "nonce doesn't solve the challenge" is invalid
+            ApiError::InvalidNonce(error)
+        } else if mentions(&["stale", "expired", "challenge", "location"]) {
+            ApiError::StaleChallenge(error)
+        } else {
+            ApiError::Status(error)
+        }
+    }
+
+    /// Whether the request is worth trying again: connection errors, timeouts, server
+    /// errors and rate limiting.
+    pub fn is_transient(&self) -> bool {
+        match self {
+            ApiError::Network(e) => e.is_connect() || e.is_request(),
+            ApiError::Timeout(_) | ApiError::RateLimited { .. } => true,
+            ApiError::Status(error) => (500..600).contains(&error.status),
+            _ => false,
+        }
+    }
+}
+
+impl From<reqwest::Error> for ApiError {
+    fn from(e: reqwest::Error) -> ApiError {
+        if e.is_timeout() {
+            ApiError::Timeout(e)
+        } else {
+            ApiError::Network(e)
+        }
+    }
+}
+
+/// What the API answered to an accepted share.
+#[derive(Debug, Clone)]
+pub struct Accepted {
+    pub status: u16,
+    pub body: String,
+}
+
 #[derive(Debug, Clone)]
 pub struct ApiClient {
     pub url: String,
     pub address: String,
+    pub chain: String,
+    pub wallet: String,
+    pub headers: Vec<(String, String)>,
+    pub client: reqwest::Client,
 }
 
 impl ApiClient {
     pub fn new(url: String, address: String) -> ApiClient {
-        ApiClient { url, address }
+        ApiClient {
+            url,
+            address,
+            chain: "BSV".to_string(),
+            wallet: "PANDA".to_string(),
+            headers: vec![],
+            client: reqwest::Client::new(),
+        }
     }
 
-    pub fn get(&self, path: String) -> reqwest::RequestBuilder {
-        let client = reqwest::Client::new();
-        client
-            .get(format!("{}{}", self.url, path))
+    fn request(&self, method: reqwest::Method, path: String) -> reqwest::RequestBuilder {
+        self.request_url(method, &format!("{}{}", self.url, path))
+    }
+
+    fn request_url(&self, method: reqwest::Method, url: &str) -> reqwest::RequestBuilder {
+        let mut builder = self
+            .client
+            .request(method, url)
             .header("Address", self.address.clone())
-            .header("Chain", "BSV")
-            .header("Wallet", "PANDA")
+            .header("Chain", self.chain.clone())
+            .header("Wallet", self.wallet.clone());
+
+        for (name, value) in &self.headers {
+            builder = builder.header(name, value);
+        }
+
+        builder
+    }
+
+    pub fn get(&self, path: String) -> reqwest::RequestBuilder {
+        self.request(reqwest::Method::GET, path)
+    }
+
+    /// GET of an absolute `url`, with the same headers as API requests.
+    pub fn get_url(&self, url: &str) -> reqwest::RequestBuilder {
+        self.request_url(reqwest::Method::GET, url)
     }
 
     pub fn post(&self, path: String) -> reqwest::RequestBuilder {
-        let client = reqwest::Client::new();
-        client
-            .post(format!("{}{}", self.url, path))
-            .header("Address", self.address.clone())
-            .header("Chain", "BSV")
-            .header("Wallet", "PANDA")
+        self.request(reqwest::Method::POST, path)
     }
 
-    pub async fn submit_share(&self, solution: &Solution) -> Result<(u16, String)> {
+    pub async fn submit_share(&self, solution: &Solution) -> Result<Accepted, ApiError> {
         let payload = json!({
-            "bsvContractLocation": "",
+            "bsvContractLocation": solution.location,
             "nonce": solution.nonce,
-            "tokenId": "c4f70f7f-aa51-4fa4-9b06-b29f45b9e73d",
+            "tokenId": solution.token_id,
             "winningHash": solution.hash
         });
 
         let res = self
-            .post(format!("/mint/save"))
+            .post("/mint/save".to_string())
             .json(&payload)
             .send()
             .await?;
 
-        let status_code = res.status().as_u16();
-        let text = res.text().await?;
+        let status = res.status().as_u16();
+        let retry_after = header(&res, reqwest::header::RETRY_AFTER);
+        // the API answers 201 Created for a saved share, anything else is a rejection;
+        // the share is saved once the status is in, so a body cut short doesn't undo that
+        if status == 201 {
+            let body = res.text().await.unwrap_or_default();
+            return Ok(Accepted { status, body });
+        }
 
-        Ok((status_code, text))
+        let body = res.text().await?;
+        Err(ApiError::from_response(
+            status,
+            body,
+            retry_after.as_deref(),
+        ))
     }
 
-    pub async fn fetch_ticker(&self, slug: &String) -> Result<Ticker> {
-        let res = self
-            .get(format!("/token/search/bsv?ticker={}", slug))
-            .send()
-            .await?
-            .json::<Value>()
-            .await?;
+    pub async fn fetch_ticker(&self, slug: &String) -> Result<Ticker, ApiError> {
+        self.fetch_ticker_cached(slug, &mut CachedTicker::default())
+            .await
+    }
 
-        let ticker: Ticker = serde_json::from_value(res)?;
+    /// Like `fetch_ticker`, but sends the validators in `cache` and returns the cached
+    /// ticker without downloading it again when the server answers 304 Not Modified.
+    pub async fn fetch_ticker_cached(
+        &self,
+        slug: &String,
+        cache: &mut CachedTicker,
+    ) -> Result<Ticker, ApiError> {
+        let mut req = self.get(format!("/token/search/bsv?ticker={}", slug));
+        // validators are only worth sending while there is a ticker to fall back on
+        if cache.ticker.is_some() {
+            if let Some(etag) = &cache.etag {
+                req = req.header(reqwest::header::IF_NONE_MATCH, etag);
+            }
+            if let Some(last_modified) = &cache.last_modified {
+                req = req.header(reqwest::header::IF_MODIFIED_SINCE, last_modified);
+            }
+        }
+
+        let res = req.send().await?;
+        if let (reqwest::StatusCode::NOT_MODIFIED, Some(ticker)) = (res.status(), &cache.ticker) {
+            return Ok(ticker.clone());
+        }
+
+        let status = res.status().as_u16();
+        let etag = header(&res, reqwest::header::ETAG);
+        let last_modified = header(&res, reqwest::header::LAST_MODIFIED);
+        let retry_after = header(&res, reqwest::header::RETRY_AFTER);
+        let body = res.text().await?;
+        if !(200..300).contains(&status) {
+            return Err(ApiError::from_response(
+                status,
+                body,
+                retry_after.as_deref(),
+            ));
+        }
+
+        let ticker = serde_json::from_str::<Ticker>(&body)
+            .map_err(|error| ApiError::InvalidResponse { error, body })?;
+        *cache = CachedTicker {
+            etag,
+            last_modified,
+            ticker: Some(ticker.clone()),
+        };
 
         Ok(ticker)
     }
 }
+
+fn header(res: &reqwest::Response, name: reqwest::header::HeaderName) -> Option<String> {
+    res.headers()
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_string)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    /// Answers `count` requests with `response` and returns the path and body of each.
+    async fn serve(
+        count: usize,
+        response: &'static str,
+    ) -> (String, tokio::task::JoinHandle<Vec<(String, String)>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+
+        let requests = tokio::spawn(async move {
+            let mut requests = vec![];
+            for _ in 0..count {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut request = vec![];
+                let mut buf = [0_u8; 4096];
+                let (head, body_len) = loop {
+                    let n = stream.read(&mut buf).await.unwrap();
+                    request.extend_from_slice(&buf[..n]);
+                    let text = String::from_utf8_lossy(&request).to_string();
+                    if let Some((head, _)) = text.split_once("\r\n\r\n") {
+                        let body_len = head
+                            .lines()
+                            .find_map(|line| {
+                                let (name, value) = line.split_once(':')?;
+                                name.eq_ignore_ascii_case("content-length")
+                                    .then(|| value.trim().parse::<usize>().unwrap())
+                            })
+                            .unwrap_or(0);
+                        break (head.to_string(), body_len);
+                    }
+                };
+                while request.len() < head.len() + 4 + body_len {
+                    let n = stream.read(&mut buf).await.unwrap();
+                    request.extend_from_slice(&buf[..n]);
+                }
+
+                let path = head.split_whitespace().nth(1).unwrap().to_string();
+                let body = String::from_utf8(request[head.len() + 4..].to_vec()).unwrap();
+                requests.push((path, body));
+
+                stream.write_all(response.as_bytes()).await.unwrap();
+                stream.shutdown().await.unwrap();
+            }
+            requests
+        });
+
+        (url, requests)
+    }
+
+    fn solution(token_id: &str, location: &str, nonce: &str, hash: &str) -> Solution {
+        Solution {
+            nonce: nonce.to_string(),
+            hash: hash.to_string(),
+            location: location.to_string(),
+            token_id: token_id.to_string(),
+            challenge: vec![0xab; 32],
+        }
+    }
+
+    #[tokio::test]
+    async fn submit_share_posts_the_solution_of_each_ticker() {
+        let solutions = [
+            solution(
+                "id-pepe",
+                "loc_pepe_0",
+                "0000000000000001",
+                "00000a07f51f41140512e21c8b35afbade6213e39a794a0dd2e0e208d3d54828",
+            ),
+            solution(
+                "id-doge",
+                "loc_doge_7",
+                "00070000045a4b17",
+                "000004f1fc2e56f8dc807e266ff0dd7d026c9d17834535fff7a9ac8c34362065",
+            ),
+        ];
+        let (url, requests) = serve(
+            solutions.len(),
+            "HTTP/1.1 201 Created\r\nContent-Length: 2\r\nConnection: close\r\n\r\nok",
+        )
+        .await;
+
+        let api_client = ApiClient::new(url, "1BoatSLRHtKNngkdXEeobR76b53LETtpyT".to_string());
+        for solution in &solutions {
+            let accepted = api_client.submit_share(solution).await.unwrap();
+            assert_eq!(accepted.status, 201);
+        }
+
+        let requests = requests.await.unwrap();
+        for ((path, body), solution) in requests.iter().zip(&solutions) {
+            assert_eq!(path, "/mint/save");
+            assert_eq!(
+                serde_json::from_str::<Value>(body).unwrap(),
+                json!({
+                    "bsvContractLocation": solution.location,
+                    "tokenId": solution.token_id,
+                    "nonce": solution.nonce,
+                    "winningHash": solution.hash,
+                })
+            );
+        }
+    }
+
+    #[test]
+    fn from_response_classifies_rejections() {
+        let kind = |error: &ApiError| match error {
+            ApiError::RateLimited { .. } => "rate_limited",
+            ApiError::StaleChallenge(_) => "stale",
+            ApiError::InvalidNonce(_) => "invalid",
+            ApiError::Duplicate(_) => "duplicate",
+            ApiError::Status(_) => "status",
+            _ => "other",
+        };
+
+        let cases = [
+            (429, "slow down", "rate_limited"),
+            (409, "conflict", "duplicate"),
+            (400, r#"{"message":"Hash already submitted"}"#, "duplicate"),
+            (400, r#"{"error":"duplicate share"}"#, "duplicate"),
+            (410, "gone", "stale"),
+            (400, r#"{"message":"challenge expired"}"#, "stale"),
+            (404, r#"{"msg":"location not found"}"#, "stale"),
+            (
+                400,
+                r#"{"message":"nonce does not solve the challenge"}"#,
+                "invalid",
+            ),
+            (400, "winning hash doesn't meet the difficulty", "invalid"),
+            (422, "invalid nonce for location", "invalid"),
+            (400, "bad request", "status"),
+            (403, "", "status"),
+            // only client errors are read for their wording
+            (
+                500,
+                r#"{"message":"challenge store unavailable"}"#,
+                "status",
+            ),
+            (502, "duplicate upstream", "status"),
+            (503, "hash service down", "status"),
+            (200, "nonce saved", "status"),
+        ];
+        for (status, body, expected) in cases {
+            let error = ApiError::from_response(status, body.to_string(), None);
+            assert_eq!(kind(&error), expected, "{} {:?}", status, body);
+        }
+
+        let error = ApiError::from_response(500, "challenge store unavailable".to_string(), None);
+        assert!(error.is_transient());
+        let error = ApiError::from_response(429, String::new(), Some("7"));
+        assert!(
+            matches!(error, ApiError::RateLimited { retry_after: Some(d), .. } if d == Duration::from_secs(7))
+        );
+    }
+}
@@ -0,0 +1,126 @@
+use super::*;
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+/// Offline hashrate measurement of the mining loop on a synthetic job.
+#[derive(Clone, Debug)]
+pub struct BenchConfig {
+    pub job: Ticker,
+    pub threads: usize,
+    pub backend: Backend,
+    /// CPUs to pin the hashing threads to, see `Miner::cpus`.
+    pub cpus: Vec<usize>,
+    /// See `Miner::nice`.
+    pub nice: i32,
+    /// Stop after this long...
+    pub duration: Option<Duration>,
+    /// ...or after this many hashes, whichever comes first.
+    pub hashes: Option<u64>,
+    /// How often the hashrate is sampled for the percentiles.
+    pub sample_interval: Duration,
+}
+
+/// Hashrates are in hashes per second.
+#[derive(Serialize, Clone, Debug)]
+pub struct BenchReport {
+    pub backend: String,
+    pub threads: usize,
+    pub difficulty: i32,
+    pub elapsed_secs: f64,
+    pub hashes: u64,
+    pub solutions: u64,
+    pub mean: f64,
+    pub p50: f64,
+    pub p99: f64,
+    pub per_thread: Vec<f64>,
+}
+
+impl BenchConfig {
+    /// A job with `challenge_len` bytes of challenge derived from `seed`, so runs are
+    /// comparable across machines and builds.
+    pub fn synthetic_job(seed: u64, challenge_len: usize, difficulty: i32) -> Ticker {
+        let mut challenge = vec![];
+        let mut counter = 0_u64;
+        while challenge.len() < challenge_len {
+            let mut block = seed.to_le_bytes().to_vec();
+            block.extend_from_slice(&counter.to_le_bytes());
+            challenge.extend_from_slice(&Hash::sha256(&block));
+            counter += 1;
+        }
+        challenge.truncate(challenge_len);
+
+        Ticker {
+            challenge: hex::encode(challenge),
+            current_location: "bench".to_string(),
+            difficulty,
+            ticker: "BENCH".to_string(),
+            id: "bench".to_string(),
+        }
+    }
+
+    pub fn run(&self) -> Result<BenchReport> {
+        if self.duration.is_none() && self.hashes.is_none() {
+            anyhow::bail!("a bench needs a duration or a hash count");
+        }
+
+        let solutions = Arc::new(AtomicU64::new(0));
+        let found = solutions.clone();
+        let miner = Miner::new(self.job.clone(), self.threads, move |_| {
+            found.fetch_add(1, Ordering::Relaxed);
+        })?
+        .backend(self.backend)
+        .cpus(self.cpus.clone())
+        .nice(self.nice);
+
+        let start = Instant::now();
+        miner.start()?;
+
+        let mut samples = vec![];
+        let mut last = (start, 0);
+        loop {
+            std::thread::sleep(self.sample_interval);
+
+            let now = Instant::now();
+            let hashes = miner.hashes();
+            samples.push((hashes - last.1) as f64 / now.duration_since(last.0).as_secs_f64());
+            last = (now, hashes);
+
+            let timed_out = self
+                .duration
+                .is_some_and(|d| now.duration_since(start) >= d);
+            let counted = self.hashes.is_some_and(|n| hashes >= n);
+            if timed_out || counted {
+                break;
+            }
+        }
+
+        miner.stop();
+        let elapsed = start.elapsed().as_secs_f64();
+        let hashes = miner.hashes();
+
+        samples.sort_by(|a, b| a.total_cmp(b));
+        let percentile = |p: f64| samples[((samples.len() - 1) as f64 * p).round() as usize];
+
+        Ok(BenchReport {
+            backend: self.backend.to_string(),
+            threads: miner.thread_hashes().len(),
+            difficulty: self.job.difficulty,
+            elapsed_secs: elapsed,
+            hashes,
+            solutions: solutions.load(Ordering::Relaxed),
+            mean: hashes as f64 / elapsed,
+            p50: percentile(0.5),
+            p99: percentile(0.99),
+            per_thread: miner
+                .thread_hashes()
+                .into_iter()
+                .map(|hashes| hashes as f64 / elapsed)
+                .collect(),
+        })
+    }
+}
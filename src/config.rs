@@ -0,0 +1,366 @@
+use super::*;
+use clap::{parser::ValueSource, Arg, ArgAction, ArgMatches, Command};
+use std::{
+    ffi::OsString,
+    path::{Path, PathBuf},
+};
+
+/// Settings read from a TOML file and applied as the defaults of a clap `Command`, so
+/// that command line flags and environment variables still take precedence. Keys are
+/// long flag names, written with `-` or `_`; flags that may be repeated take arrays.
+#[derive(Clone, Debug)]
+pub struct Config {
+    path: PathBuf,
+    /// Argument id and value of every key in the file, in file order.
+    values: Vec<(String, toml::Value)>,
+}
+
+impl Config {
+    /// The file named by `--config <FILE>` or `--config=<FILE>` in `args`, falling back
+    /// to the `env` environment variable.
+    pub fn find(args: &[OsString], env: &str) -> Option<PathBuf> {
+        let mut args = args.iter().skip(1).take_while(|arg| *arg != "--");
+        while let Some(arg) = args.next() {
+            if arg == "--config" {
+                return args.next().map(PathBuf::from);
+            }
+            if let Some(path) = arg.to_str().and_then(|arg| arg.strip_prefix("--config=")) {
+                return Some(PathBuf::from(path));
+            }
+        }
+
+        std::env::var_os(env)
+            .filter(|path| !path.is_empty())
+            .map(PathBuf::from)
+    }
+
+    /// Reads the file at `path` and checks every key and value against the arguments
+    /// of `command`.
+    pub fn load(path: &Path, command: &Command) -> Result<Config> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| anyhow::anyhow!("{}: {}", path.display(), e))?;
+        let table = text
+            .parse::<toml::Table>()
+            .map_err(|e| anyhow::anyhow!("{}: {}", path.display(), e))?;
+
+        let mut values = vec![];
+        for (key, value) in table {
+            let long = key.replace('_', "-");
+            let Some(arg) = configurable(command).find(|arg| arg.get_long() == Some(&long)) else {
+                let keys = configurable(command)
+                    .filter_map(Arg::get_long)
+                    .collect::<Vec<_>>()
+                    .join(", ");
+                anyhow::bail!(
+                    "{}: unknown key `{}`, expected one of: {}",
+                    path.display(),
+                    key,
+                    keys
+                );
+            };
+
+            let strings = to_strings(&value, is_repeated(arg))
+                .map_err(|e| anyhow::anyhow!("{}: `{}` {}", path.display(), key, e))?;
+            validate(arg, &strings).map_err(|e| anyhow::anyhow!("{}: {}", path.display(), e))?;
+
+            values.push((arg.get_id().to_string(), value));
+        }
+
+        Ok(Config {
+            path: path.to_path_buf(),
+            values,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// `command` with the values of this file as its defaults.
+    pub fn apply(&self, mut command: Command) -> Command {
+        for (id, value) in &self.values {
+            // checked by `load`
+            let strings = to_strings(value, true).unwrap_or_default();
+            // a required flag is satisfied by the file
+            command = command.mut_arg(id, |arg| arg.default_values(strings).required(false));
+        }
+
+        command
+    }
+
+    /// The effective value of every configurable argument of `command` as TOML, each
+    /// followed by a comment saying where it came from. `matches` must come from
+    /// `command` with `config` applied; `command` itself is only used for the list and
+    /// order of the arguments.
+    pub fn render(command: &Command, matches: &ArgMatches, config: Option<&Config>) -> String {
+        let mut out = String::new();
+        if let Some(config) = config {
+            out.push_str(&format!("# merged with {}\n", config.path.display()));
+        }
+
+        for arg in configurable(command) {
+            let id = arg.get_id().as_str();
+            // global flags given after a subcommand only show up in its matches
+            let matches = match matches.subcommand() {
+                Some((_, sub))
+                    if sub.try_get_raw(id).is_ok_and(|raw| raw.is_some())
+                        && sub.value_source(id) != Some(ValueSource::DefaultValue) =>
+                {
+                    sub
+                }
+                _ => matches,
+            };
+
+            let Some(source) = matches.value_source(id) else {
+                continue;
+            };
+            let from_config =
+                config.and_then(|config| config.values.iter().find(|(key, _)| key == id));
+
+            let (value, source) = match (source, from_config) {
+                (ValueSource::DefaultValue, Some((_, value))) => (value.clone(), "config"),
+                (source, _) => {
+                    let raw = matches
+                        .get_raw(id)
+                        .into_iter()
+                        .flatten()
+                        .map(|value| value.to_string_lossy().to_string())
+                        .collect::<Vec<_>>();
+                    let source = match source {
+                        ValueSource::CommandLine => "command line",
+                        ValueSource::EnvVariable => "environment",
+                        _ => "default",
+                    };
+                    (to_value(raw, is_repeated(arg)), source)
+                }
+            };
+
+            out.push_str(&format!(
+                "{} = {} # {}\n",
+                arg.get_long().unwrap_or(id),
+                value,
+                source
+            ));
+        }
+
+        out
+    }
+}
+
+/// Arguments of `command` that can be set from a config file.
+fn configurable(command: &Command) -> impl Iterator<Item = &Arg> {
+    command.get_arguments().filter(|arg| {
+        arg.get_long().is_some()
+            && !matches!(arg.get_id().as_str(), "config" | "help" | "version")
+            && !matches!(arg.get_action(), ArgAction::Help | ArgAction::Version)
+    })
+}
+
+fn is_repeated(arg: &Arg) -> bool {
+    matches!(arg.get_action(), ArgAction::Append)
+}
+
+fn to_strings(value: &toml::Value, repeated: bool) -> Result<Vec<String>> {
+    match value {
+        toml::Value::String(s) => Ok(vec![s.clone()]),
+        toml::Value::Integer(i) => Ok(vec![i.to_string()]),
+        toml::Value::Float(f) => Ok(vec![f.to_string()]),
+        toml::Value::Boolean(b) => Ok(vec![b.to_string()]),
+        toml::Value::Array(values) if repeated => values
+            .iter()
+            .map(|value| match value {
+                toml::Value::Array(_) | toml::Value::Table(_) => {
+                    anyhow::bail!("must be an array of plain values")
+                }
+                value => Ok(to_strings(value, false)?.remove(0)),
+            })
+            .collect(),
+        toml::Value::Array(_) => anyhow::bail!("takes a single value, not an array"),
+        toml::Value::Table(_) => anyhow::bail!("takes a value, not a table"),
+        toml::Value::Datetime(_) => anyhow::bail!("takes a value, not a date"),
+    }
+}
+
+/// The TOML for the raw values of an argument, typed the way they look.
+fn to_value(raw: Vec<String>, repeated: bool) -> toml::Value {
+    if repeated {
+        return toml::Value::Array(raw.into_iter().map(toml::Value::String).collect());
+    }
+
+    let raw = raw.into_iter().next().unwrap_or_default();
+    if let Ok(i) = raw.parse::<i64>() {
+        toml::Value::Integer(i)
+    } else if let Ok(f) = raw.parse::<f64>() {
+        toml::Value::Float(f)
+    } else if let Ok(b) = raw.parse::<bool>() {
+        toml::Value::Boolean(b)
+    } else {
+        toml::Value::String(raw)
+    }
+}
+
+/// Parses `values` with the value parser of `arg`, so that a bad value is reported
+/// against the config file rather than as if it came from the command line.
+fn validate(arg: &Arg, values: &[String]) -> Result<()> {
+    if matches!(arg.get_action(), ArgAction::SetTrue | ArgAction::SetFalse) {
+        if values.iter().any(|value| value.parse::<bool>().is_err()) {
+            anyhow::bail!("`{}` must be true or false", arg.get_id());
+        }
+        return Ok(());
+    }
+
+    let long = arg.get_long().unwrap_or_default();
+    let probe = Command::new("config").no_binary_name(true).arg(
+        Arg::new(arg.get_id().clone())
+            .long(long.to_string())
+            .value_names(arg.get_value_names().unwrap_or_default().to_vec())
+            .action(arg.get_action().clone())
+            .value_parser(arg.get_value_parser().clone()),
+    );
+
+    let mut argv = vec![];
+    for value in values {
+        argv.push(format!("--{}={}", long, value));
+    }
+
+    if let Err(e) = probe.try_get_matches_from(argv) {
+        let message = e.to_string();
+        let message = message
+            .lines()
+            .take_while(|line| !line.is_empty())
+            .collect::<Vec<_>>()
+            .join("\n");
+        anyhow::bail!("{}", message.trim_start_matches("error: "));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn command() -> Command {
+        Command::new("test")
+            .no_binary_name(true)
+            .arg(
+                Arg::new("threads")
+                    .long("threads")
+                    .env("POW20_CONFIG_TEST_THREADS")
+                    .value_parser(clap::value_parser!(usize))
+                    .default_value("1"),
+            )
+            .arg(
+                Arg::new("ticks")
+                    .long("tick")
+                    .action(ArgAction::Append)
+                    .required(true),
+            )
+            .arg(Arg::new("dedup_window").long("dedup-window"))
+    }
+
+    fn load(name: &str, text: &str) -> (PathBuf, Result<Config>) {
+        let path =
+            std::env::temp_dir().join(format!("pow20-config-{}-{}.toml", name, std::process::id()));
+        std::fs::write(&path, text).unwrap();
+        let config = Config::load(&path, &command());
+        std::fs::remove_file(&path).unwrap();
+        (path, config)
+    }
+
+    #[test]
+    fn config_is_overridden_by_env_and_flags() {
+        let (_, config) = load("override", "threads = 4\ntick = \"PEPE\"\n");
+        let from_file = config.unwrap().apply(command());
+
+        let matches = from_file.try_get_matches_from(["--tick=DOGE"]).unwrap();
+        assert_eq!(matches.get_one::<usize>("threads"), Some(&4));
+        assert_eq!(
+            matches.value_source("threads"),
+            Some(ValueSource::DefaultValue)
+        );
+        let ticks = matches
+            .get_many::<String>("ticks")
+            .unwrap()
+            .collect::<Vec<_>>();
+        assert_eq!(ticks, ["DOGE"]);
+
+        // clap reads the variable when the argument is built; no other test touches it
+        std::env::set_var("POW20_CONFIG_TEST_THREADS", "8");
+        let (_, config) = load("override-env", "threads = 4\ntick = \"PEPE\"\n");
+        let from_env = config.unwrap().apply(command());
+        std::env::remove_var("POW20_CONFIG_TEST_THREADS");
+
+        let matches = from_env.clone().try_get_matches_from(Vec::<&str>::new());
+        let from_flag = from_env.try_get_matches_from(["--threads=16"]);
+        let matches = matches.unwrap();
+        assert_eq!(matches.get_one::<usize>("threads"), Some(&8));
+        assert_eq!(
+            matches.value_source("threads"),
+            Some(ValueSource::EnvVariable)
+        );
+        let ticks = matches
+            .get_many::<String>("ticks")
+            .unwrap()
+            .collect::<Vec<_>>();
+        assert_eq!(ticks, ["PEPE"]);
+        assert_eq!(from_flag.unwrap().get_one::<usize>("threads"), Some(&16));
+    }
+
+    #[test]
+    fn unknown_keys_are_reported_against_the_file() {
+        let (path, config) = load("unknown", "thread = 4\n");
+        let e = config.unwrap_err().to_string();
+        assert!(e.starts_with(&path.display().to_string()), "{}", e);
+        assert!(e.contains("unknown key `thread`"), "{}", e);
+        assert!(e.contains("threads, tick, dedup-window"), "{}", e);
+
+        // `_` and `-` both name the same flag
+        let (_, config) = load("underscore", "dedup_window = 5\n");
+        assert!(config.is_ok());
+    }
+
+    #[test]
+    fn bad_values_are_reported_against_the_file() {
+        let (path, config) = load("bad", "threads = \"many\"\n");
+        let e = config.unwrap_err().to_string();
+        assert!(e.starts_with(&path.display().to_string()), "{}", e);
+        assert!(e.contains("'many'"), "{}", e);
+        assert!(!e.contains("Usage"), "{}", e);
+    }
+
+    #[test]
+    fn arrays_only_for_repeated_keys() {
+        let (_, config) = load("array", "tick = [\"PEPE\", \"DOGE\"]\n");
+        let matches = config
+            .unwrap()
+            .apply(command())
+            .try_get_matches_from(Vec::<&str>::new())
+            .unwrap();
+        let ticks = matches
+            .get_many::<String>("ticks")
+            .unwrap()
+            .collect::<Vec<_>>();
+        assert_eq!(ticks, ["PEPE", "DOGE"]);
+
+        // a single value is fine for a repeated flag too
+        let (_, config) = load("single", "tick = \"PEPE\"\n");
+        assert!(config.is_ok());
+
+        let (_, config) = load("scalar", "threads = [1, 2]\n");
+        let e = config.unwrap_err().to_string();
+        assert!(
+            e.contains("`threads` takes a single value, not an array"),
+            "{}",
+            e
+        );
+
+        let (_, config) = load("nested", "tick = [[\"PEPE\"]]\n");
+        let e = config.unwrap_err().to_string();
+        assert!(
+            e.contains("`tick` must be an array of plain values"),
+            "{}",
+            e
+        );
+    }
+}
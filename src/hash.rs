@@ -1,4 +1,17 @@
+use sha2::digest::generic_array::GenericArray;
 use sha2::{Digest, Sha256};
+use std::{
+    fmt,
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+#[cfg(target_arch = "x86_64")]
+mod x86;
+
+const SHA256_IV: [u32; 8] = [
+    0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab, 0x5be0cd19,
+];
 
 pub struct Hash {}
 
@@ -10,10 +23,395 @@ impl Hash {
     }
 
     pub fn sha256(data: &[u8]) -> [u8; 32] {
-        Hash::sha256_bytes(data).into()
+        Hash::sha256_bytes(data)
     }
 
     pub fn sha256d(data: &[u8]) -> [u8; 32] {
         Hash::sha256_bytes(&Hash::sha256_bytes(data))
     }
+
+    /// Absorbs the fixed `prefix` of a job once, so that `sha256d(prefix || nonce)`
+    /// can then be computed per nonce without rehashing the prefix.
+    pub fn prepare(prefix: &[u8]) -> PreparedJob {
+        let full_blocks = prefix.len() / 64;
+
+        let mut midstate = SHA256_IV;
+        for block in prefix[..full_blocks * 64].chunks_exact(64) {
+            sha2::compress256(&mut midstate, &[*GenericArray::from_slice(block)]);
+        }
+
+        let rest = &prefix[full_blocks * 64..];
+        let message_len = rest.len() + 8;
+        // the length field needs 8 bytes after the 0x80 marker
+        let tail_len = if message_len + 9 <= 64 { 64 } else { 128 };
+
+        let mut tail = [0_u8; 128];
+        tail[..rest.len()].copy_from_slice(rest);
+        tail[message_len] = 0x80;
+        let bit_len = ((prefix.len() + 8) as u64) * 8;
+        tail[tail_len - 8..tail_len].copy_from_slice(&bit_len.to_be_bytes());
+
+        let mut tail_words = [0_u32; 32];
+        for (word, chunk) in tail_words.iter_mut().zip(tail.chunks_exact(4)) {
+            *word = u32::from_be_bytes(chunk.try_into().unwrap());
+        }
+
+        PreparedJob {
+            midstate,
+            tail,
+            tail_words,
+            tail_len,
+            nonce_offset: rest.len(),
+        }
+    }
+}
+
+/// A SHA-256d job with its prefix already compressed into a midstate; only the
+/// block(s) holding the 8-byte nonce are compressed per attempt.
+#[derive(Clone, Debug)]
+pub struct PreparedJob {
+    midstate: [u32; 8],
+    tail: [u8; 128],
+    tail_words: [u32; 32],
+    tail_len: usize,
+    nonce_offset: usize,
+}
+
+impl PreparedJob {
+    pub fn sha256d(&self, nonce: &[u8; 8]) -> [u8; 32] {
+        let mut tail = self.tail;
+        tail[self.nonce_offset..self.nonce_offset + 8].copy_from_slice(nonce);
+
+        let mut state = self.midstate;
+        for block in tail[..self.tail_len].chunks_exact(64) {
+            sha2::compress256(&mut state, &[*GenericArray::from_slice(block)]);
+        }
+
+        let mut block = [0_u8; 64];
+        for (chunk, word) in block[..32].chunks_exact_mut(4).zip(state) {
+            chunk.copy_from_slice(&word.to_be_bytes());
+        }
+        block[32] = 0x80;
+        block[62] = 0x01;
+
+        let mut state = SHA256_IV;
+        sha2::compress256(&mut state, &[GenericArray::from(block)]);
+
+        let mut hash = [0_u8; 32];
+        for (chunk, word) in hash.chunks_exact_mut(4).zip(state) {
+            chunk.copy_from_slice(&word.to_be_bytes());
+        }
+        hash
+    }
+
+    /// Hashes every nonce in `nonces` into the matching slot of `hashes`, `backend.lanes()`
+    /// nonces per call. Leftovers that don't fill a whole call go through the scalar path.
+    pub fn sha256d_many(&self, backend: Backend, nonces: &[[u8; 8]], hashes: &mut [[u8; 32]]) {
+        assert_eq!(nonces.len(), hashes.len());
+        assert!(
+            backend.is_supported(),
+            "{} is not supported by this CPU",
+            backend
+        );
+
+        let lanes = backend.lanes();
+        let whole = nonces.len() - nonces.len() % lanes;
+
+        for (nonces, hashes) in nonces[..whole]
+            .chunks_exact(lanes)
+            .zip(hashes[..whole].chunks_exact_mut(lanes))
+        {
+            match backend {
+                #[cfg(target_arch = "x86_64")]
+                Backend::ShaNi => unsafe { x86::shani::sha256d(self, nonces, hashes) },
+                #[cfg(target_arch = "x86_64")]
+                Backend::Sse2 => unsafe { x86::sse2::sha256d(self, nonces, hashes) },
+                #[cfg(target_arch = "x86_64")]
+                Backend::Avx2 => unsafe { x86::avx2::sha256d(self, nonces, hashes) },
+                #[cfg(target_arch = "x86_64")]
+                Backend::Avx512 => unsafe { x86::avx512::sha256d(self, nonces, hashes) },
+                _ => hashes[0] = self.sha256d(&nonces[0]),
+            }
+        }
+
+        for (nonce, hash) in nonces[whole..].iter().zip(&mut hashes[whole..]) {
+            *hash = self.sha256d(nonce);
+        }
+    }
+}
+
+/// A difficulty of `n` leading zero nibbles as masks over the hash's four big-endian
+/// 64-bit words, so a hash can be checked with a few word compares.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Target {
+    masks: [u64; 4],
+}
+
+impl Target {
+    /// Difficulties outside `0..=64` are clamped.
+    pub fn new(difficulty: i32) -> Target {
+        let bits = difficulty.clamp(0, 64) as u32 * 4;
+
+        let mut masks = [0_u64; 4];
+        for (k, mask) in masks.iter_mut().enumerate() {
+            let word_bits = bits.saturating_sub(k as u32 * 64).min(64);
+            *mask = u64::MAX.checked_shl(64 - word_bits).unwrap_or(0);
+        }
+
+        Target { masks }
+    }
+}
+
+/// Whether `hash` starts with at least as many zero nibbles as `target` asks for.
+pub fn meets_difficulty(hash: &[u8; 32], target: &Target) -> bool {
+    target
+        .masks
+        .iter()
+        .zip(hash.chunks_exact(8))
+        .take_while(|(mask, _)| **mask != 0)
+        .all(|(mask, word)| u64::from_be_bytes(word.try_into().unwrap()) & mask == 0)
+}
+
+/// Number of zero nibbles `hash` starts with.
+pub fn leading_zero_nibbles(hash: &[u8; 32]) -> u32 {
+    let mut zeros = 0;
+    for word in hash.chunks_exact(8) {
+        let word = u64::from_be_bytes(word.try_into().unwrap());
+        zeros += word.leading_zeros() / 4;
+        if word != 0 {
+            break;
+        }
+    }
+    zeros
+}
+
+/// How long `Backend::detect` times each backend.
+const DETECT_TIME: Duration = Duration::from_millis(20);
+
+/// Implementation used by `PreparedJob::sha256d_many`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Backend {
+    /// One nonce at a time through `sha2`.
+    Scalar,
+    /// 2 nonces per call with the SHA extensions.
+    ShaNi,
+    /// 4 nonces per call.
+    Sse2,
+    /// 8 nonces per call.
+    Avx2,
+    /// 16 nonces per call.
+    Avx512,
+}
+
+impl Backend {
+    pub const ALL: [Backend; 5] = [
+        Backend::Scalar,
+        Backend::ShaNi,
+        Backend::Sse2,
+        Backend::Avx2,
+        Backend::Avx512,
+    ];
+
+    /// Picks the fastest backend the running CPU supports.
+    /// The fastest backend the CPU supports, timed for a few milliseconds each. `Scalar`
+    /// hashes through `sha2`, which uses the SHA extensions where there are any, so wider
+    /// backends don't always beat it; another one is only picked if it measured faster.
+    pub fn detect() -> Backend {
+        let job = Hash::prepare(&[0; 32]);
+        let nonces = (0..256_u64).map(u64::to_be_bytes).collect::<Vec<_>>();
+        let mut hashes = vec![[0; 32]; nonces.len()];
+        let mut rate = |backend| {
+            let start = Instant::now();
+            let mut hashed = 0;
+            while start.elapsed() < DETECT_TIME {
+                job.sha256d_many(backend, &nonces, &mut hashes);
+                hashed += nonces.len();
+            }
+            hashed as f64 / start.elapsed().as_secs_f64()
+        };
+
+        let mut best = (Backend::Scalar, rate(Backend::Scalar));
+        for backend in Backend::ALL {
+            if backend == Backend::Scalar || !backend.is_supported() {
+                continue;
+            }
+            let hashrate = rate(backend);
+            if hashrate > best.1 {
+                best = (backend, hashrate);
+            }
+        }
+        best.0
+    }
+
+    pub fn is_supported(self) -> bool {
+        match self {
+            Backend::Scalar => true,
+            #[cfg(target_arch = "x86_64")]
+            Backend::ShaNi => {
+                is_x86_feature_detected!("sha")
+                    && is_x86_feature_detected!("sse2")
+                    && is_x86_feature_detected!("ssse3")
+                    && is_x86_feature_detected!("sse4.1")
+            }
+            #[cfg(target_arch = "x86_64")]
+            Backend::Sse2 => is_x86_feature_detected!("sse2"),
+            #[cfg(target_arch = "x86_64")]
+            Backend::Avx2 => is_x86_feature_detected!("avx2"),
+            #[cfg(target_arch = "x86_64")]
+            Backend::Avx512 => is_x86_feature_detected!("avx512f"),
+            #[cfg(not(target_arch = "x86_64"))]
+            _ => false,
+        }
+    }
+
+    pub fn lanes(self) -> usize {
+        match self {
+            Backend::Scalar => 1,
+            Backend::ShaNi => 2,
+            Backend::Sse2 => 4,
+            Backend::Avx2 => 8,
+            Backend::Avx512 => 16,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Backend::Scalar => "scalar",
+            Backend::ShaNi => "sha-ni",
+            Backend::Sse2 => "sse2",
+            Backend::Avx2 => "avx2",
+            Backend::Avx512 => "avx512",
+        }
+    }
+}
+
+impl fmt::Display for Backend {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for Backend {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Backend, String> {
+        Backend::ALL
+            .into_iter()
+            .find(|backend| backend.name() == s)
+            .ok_or_else(|| {
+                let names = Backend::ALL.map(Backend::name).join(", ");
+                format!("unknown backend {:?}, expected one of: {}", s, names)
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{rngs::SmallRng, Rng, SeedableRng};
+
+    /// `sha256d(prefix || nonce)` the way it was computed before midstates.
+    fn preimage_sha256d(prefix: &[u8], nonce: &[u8; 8]) -> [u8; 32] {
+        let mut preimage = prefix.to_vec();
+        preimage.extend_from_slice(nonce);
+        Hash::sha256d(&preimage)
+    }
+
+    #[test]
+    fn prepared_job_matches_full_preimage() {
+        let mut rng = SmallRng::seed_from_u64(3);
+        // 55/56 and 63/64 put the nonce and the length field on either side of a block
+        // boundary, 120 needs two tail blocks after a compressed one
+        let lens = [
+            0, 1, 32, 47, 48, 55, 56, 57, 63, 64, 65, 119, 120, 121, 128, 200,
+        ];
+        let lens = lens
+            .into_iter()
+            .chain((0..64).map(|_| rng.gen_range(0..256)))
+            .collect::<Vec<usize>>();
+
+        for len in lens {
+            let mut prefix = vec![0_u8; len];
+            rng.fill(&mut prefix[..]);
+            let job = Hash::prepare(&prefix);
+
+            for _ in 0..8 {
+                let nonce = rng.gen::<[u8; 8]>();
+                assert_eq!(
+                    job.sha256d(&nonce),
+                    preimage_sha256d(&prefix, &nonce),
+                    "prefix {} nonce {}",
+                    hex::encode(&prefix),
+                    hex::encode(nonce)
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn backends_match_scalar() {
+        let mut rng = SmallRng::seed_from_u64(4);
+        // 37 nonces leave leftovers for every lane count above one
+        let nonces = (0..37).map(|_| rng.gen::<[u8; 8]>()).collect::<Vec<_>>();
+
+        let lens = [
+            0, 1, 8, 31, 32, 33, 47, 48, 55, 56, 57, 63, 64, 65, 100, 119, 120, 128,
+        ];
+        for len in lens {
+            let mut prefix = vec![0_u8; len];
+            rng.fill(&mut prefix[..]);
+            let job = Hash::prepare(&prefix);
+            let expected = nonces
+                .iter()
+                .map(|nonce| job.sha256d(nonce))
+                .collect::<Vec<_>>();
+
+            for backend in Backend::ALL {
+                if !backend.is_supported() {
+                    eprintln!("skipping {}: not supported by this CPU", backend);
+                    continue;
+                }
+                let mut hashes = vec![[0_u8; 32]; nonces.len()];
+                job.sha256d_many(backend, &nonces, &mut hashes);
+                assert_eq!(hashes, expected, "{} with a {} byte prefix", backend, len);
+            }
+        }
+    }
+
+    #[test]
+    fn target_matches_nibble_loop() {
+        // how difficulty was checked before targets
+        let nibble_loop = |hash: &[u8; 32], difficulty: i32| {
+            (0..difficulty).all(|i| {
+                let rshift = (1 - (i % 2)) << 2;
+                (hash[(i / 2) as usize] >> rshift) & 0x0f == 0
+            })
+        };
+
+        let mut rng = SmallRng::seed_from_u64(5);
+        for zeros in 0..=64 {
+            for _ in 0..4 {
+                // exactly `zeros` leading zero nibbles, random after that
+                let mut hash = rng.gen::<[u8; 32]>();
+                for i in 0..zeros {
+                    hash[i / 2] &= if i % 2 == 0 { 0x0f } else { 0xf0 };
+                }
+                if zeros < 64 {
+                    let nibble = rng.gen_range(1..16_u8);
+                    hash[zeros / 2] |= if zeros % 2 == 0 { nibble << 4 } else { nibble };
+                }
+                assert_eq!(leading_zero_nibbles(&hash), zeros as u32);
+
+                for difficulty in 0..=64 {
+                    assert_eq!(
+                        meets_difficulty(&hash, &Target::new(difficulty)),
+                        nibble_loop(&hash, difficulty),
+                        "hash {} difficulty {}",
+                        hex::encode(hash),
+                        difficulty
+                    );
+                }
+            }
+        }
+    }
 }
@@ -0,0 +1,439 @@
+//! x86_64 SHA-256d kernels. Every function here assumes the caller has checked
+//! `Backend::is_supported` for the matching backend.
+
+use super::{PreparedJob, SHA256_IV};
+
+const K: [u32; 64] = [
+    0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4, 0xab1c5ed5,
+    0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174,
+    0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
+    0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7, 0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967,
+    0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85,
+    0xa2bfe8a1, 0xa81a664b, 0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
+    0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+    0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2,
+];
+
+/// `rotr::<N, L>` with `L = 32 - N`, which const generics can't work out themselves.
+/// Shift counts are immediates: with a count in a register, as `_mm_srl_epi32` takes
+/// it, the vector kernels ran slower than scalar.
+macro_rules! rotr {
+    ($v:expr, $n:literal) => {
+        rotr::<$n, { 32 - $n }>($v)
+    };
+}
+
+/// Expands to a `sha256d` that hashes `LANES` nonces at once, one nonce per 32-bit
+/// lane. The invoking module provides the vector type `V` and its primitives.
+macro_rules! multiway {
+    ($feature:literal) => {
+        #[inline]
+        #[target_feature(enable = $feature)]
+        unsafe fn compress(state: &mut [V; 8], block: &[V; 16]) {
+            let mut w = *block;
+            let [mut a, mut b, mut c, mut d, mut e, mut f, mut g, mut h] = *state;
+
+            for (t, k) in K.iter().enumerate() {
+                if t >= 16 {
+                    let w15 = w[(t - 15) & 15];
+                    let w2 = w[(t - 2) & 15];
+                    let s0 = xor(xor(rotr!(w15, 7), rotr!(w15, 18)), shr::<3>(w15));
+                    let s1 = xor(xor(rotr!(w2, 17), rotr!(w2, 19)), shr::<10>(w2));
+                    w[t & 15] = add(add(w[t & 15], s0), add(w[(t - 7) & 15], s1));
+                }
+
+                let s1 = xor(xor(rotr!(e, 6), rotr!(e, 11)), rotr!(e, 25));
+                let ch = xor(and(e, f), andnot(e, g));
+                let t1 = add(add(add(h, s1), add(ch, splat(*k))), w[t & 15]);
+                let s0 = xor(xor(rotr!(a, 2), rotr!(a, 13)), rotr!(a, 22));
+                let maj = or(and(a, b), and(c, or(a, b)));
+                let t2 = add(s0, maj);
+
+                h = g;
+                g = f;
+                f = e;
+                e = add(d, t1);
+                d = c;
+                c = b;
+                b = a;
+                a = add(t1, t2);
+            }
+
+            for (word, v) in state.iter_mut().zip([a, b, c, d, e, f, g, h]) {
+                *word = add(*word, v);
+            }
+        }
+
+        #[target_feature(enable = $feature)]
+        pub unsafe fn sha256d(job: &PreparedJob, nonces: &[[u8; 8]], hashes: &mut [[u8; 32]]) {
+            // the nonce touches at most 3 big-endian words of the tail, only those differ per lane
+            let first = job.nonce_offset / 4;
+            let last = (job.nonce_offset + 7) / 4;
+            let mut varying = [[0_u32; LANES]; 3];
+            for (lane, nonce) in nonces.iter().enumerate() {
+                let mut tail = job.tail;
+                tail[job.nonce_offset..job.nonce_offset + 8].copy_from_slice(nonce);
+                for (i, words) in varying.iter_mut().enumerate().take(last - first + 1) {
+                    let at = (first + i) * 4;
+                    words[lane] = u32::from_be_bytes(tail[at..at + 4].try_into().unwrap());
+                }
+            }
+
+            let mut state = [splat(0); 8];
+            for (v, word) in state.iter_mut().zip(job.midstate) {
+                *v = splat(word);
+            }
+
+            for b in 0..job.tail_len / 64 {
+                let mut block = [splat(0); 16];
+                for (i, v) in block.iter_mut().enumerate() {
+                    let at = b * 16 + i;
+                    *v = if (first..=last).contains(&at) {
+                        load(&varying[at - first])
+                    } else {
+                        splat(job.tail_words[at])
+                    };
+                }
+                compress(&mut state, &block);
+            }
+
+            let mut block = [splat(0); 16];
+            block[..8].copy_from_slice(&state);
+            block[8] = splat(0x80000000);
+            block[15] = splat(256);
+
+            let mut state = [splat(0); 8];
+            for (v, word) in state.iter_mut().zip(SHA256_IV) {
+                *v = splat(word);
+            }
+            compress(&mut state, &block);
+
+            for (i, v) in state.iter().enumerate() {
+                for (hash, word) in hashes.iter_mut().zip(store(*v)) {
+                    hash[i * 4..i * 4 + 4].copy_from_slice(&word.to_be_bytes());
+                }
+            }
+        }
+    };
+}
+
+pub mod sse2 {
+    use super::*;
+    use std::arch::x86_64::*;
+
+    type V = __m128i;
+    const LANES: usize = 4;
+
+    #[inline]
+    #[target_feature(enable = "sse2")]
+    unsafe fn add(a: V, b: V) -> V {
+        _mm_add_epi32(a, b)
+    }
+
+    #[inline]
+    #[target_feature(enable = "sse2")]
+    unsafe fn xor(a: V, b: V) -> V {
+        _mm_xor_si128(a, b)
+    }
+
+    #[inline]
+    #[target_feature(enable = "sse2")]
+    unsafe fn and(a: V, b: V) -> V {
+        _mm_and_si128(a, b)
+    }
+
+    #[inline]
+    #[target_feature(enable = "sse2")]
+    unsafe fn or(a: V, b: V) -> V {
+        _mm_or_si128(a, b)
+    }
+
+    /// `!a & b`
+    #[inline]
+    #[target_feature(enable = "sse2")]
+    unsafe fn andnot(a: V, b: V) -> V {
+        _mm_andnot_si128(a, b)
+    }
+
+    #[inline]
+    #[target_feature(enable = "sse2")]
+    unsafe fn shr<const N: i32>(a: V) -> V {
+        _mm_srli_epi32::<N>(a)
+    }
+
+    /// Rotates right by `N`, `L` is `32 - N`.
+    #[inline]
+    #[target_feature(enable = "sse2")]
+    unsafe fn rotr<const N: i32, const L: i32>(a: V) -> V {
+        _mm_or_si128(_mm_srli_epi32::<N>(a), _mm_slli_epi32::<L>(a))
+    }
+
+    #[inline]
+    #[target_feature(enable = "sse2")]
+    unsafe fn splat(word: u32) -> V {
+        _mm_set1_epi32(word as i32)
+    }
+
+    #[inline]
+    #[target_feature(enable = "sse2")]
+    unsafe fn load(words: &[u32; LANES]) -> V {
+        _mm_loadu_si128(words.as_ptr() as *const V)
+    }
+
+    #[inline]
+    #[target_feature(enable = "sse2")]
+    unsafe fn store(v: V) -> [u32; LANES] {
+        let mut words = [0; LANES];
+        _mm_storeu_si128(words.as_mut_ptr() as *mut V, v);
+        words
+    }
+
+    multiway!("sse2");
+}
+
+pub mod avx2 {
+    use super::*;
+    use std::arch::x86_64::*;
+
+    type V = __m256i;
+    const LANES: usize = 8;
+
+    #[inline]
+    #[target_feature(enable = "avx2")]
+    unsafe fn add(a: V, b: V) -> V {
+        _mm256_add_epi32(a, b)
+    }
+
+    #[inline]
+    #[target_feature(enable = "avx2")]
+    unsafe fn xor(a: V, b: V) -> V {
+        _mm256_xor_si256(a, b)
+    }
+
+    #[inline]
+    #[target_feature(enable = "avx2")]
+    unsafe fn and(a: V, b: V) -> V {
+        _mm256_and_si256(a, b)
+    }
+
+    #[inline]
+    #[target_feature(enable = "avx2")]
+    unsafe fn or(a: V, b: V) -> V {
+        _mm256_or_si256(a, b)
+    }
+
+    /// `!a & b`
+    #[inline]
+    #[target_feature(enable = "avx2")]
+    unsafe fn andnot(a: V, b: V) -> V {
+        _mm256_andnot_si256(a, b)
+    }
+
+    #[inline]
+    #[target_feature(enable = "avx2")]
+    unsafe fn shr<const N: i32>(a: V) -> V {
+        _mm256_srli_epi32::<N>(a)
+    }
+
+    /// Rotates right by `N`, `L` is `32 - N`.
+    #[inline]
+    #[target_feature(enable = "avx2")]
+    unsafe fn rotr<const N: i32, const L: i32>(a: V) -> V {
+        _mm256_or_si256(_mm256_srli_epi32::<N>(a), _mm256_slli_epi32::<L>(a))
+    }
+
+    #[inline]
+    #[target_feature(enable = "avx2")]
+    unsafe fn splat(word: u32) -> V {
+        _mm256_set1_epi32(word as i32)
+    }
+
+    #[inline]
+    #[target_feature(enable = "avx2")]
+    unsafe fn load(words: &[u32; LANES]) -> V {
+        _mm256_loadu_si256(words.as_ptr() as *const V)
+    }
+
+    #[inline]
+    #[target_feature(enable = "avx2")]
+    unsafe fn store(v: V) -> [u32; LANES] {
+        let mut words = [0; LANES];
+        _mm256_storeu_si256(words.as_mut_ptr() as *mut V, v);
+        words
+    }
+
+    multiway!("avx2");
+}
+
+pub mod avx512 {
+    use super::*;
+    use std::arch::x86_64::*;
+
+    type V = __m512i;
+    const LANES: usize = 16;
+
+    #[inline]
+    #[target_feature(enable = "avx512f")]
+    unsafe fn add(a: V, b: V) -> V {
+        _mm512_add_epi32(a, b)
+    }
+
+    #[inline]
+    #[target_feature(enable = "avx512f")]
+    unsafe fn xor(a: V, b: V) -> V {
+        _mm512_xor_si512(a, b)
+    }
+
+    #[inline]
+    #[target_feature(enable = "avx512f")]
+    unsafe fn and(a: V, b: V) -> V {
+        _mm512_and_si512(a, b)
+    }
+
+    #[inline]
+    #[target_feature(enable = "avx512f")]
+    unsafe fn or(a: V, b: V) -> V {
+        _mm512_or_si512(a, b)
+    }
+
+    /// `!a & b`
+    #[inline]
+    #[target_feature(enable = "avx512f")]
+    unsafe fn andnot(a: V, b: V) -> V {
+        _mm512_andnot_si512(a, b)
+    }
+
+    #[inline]
+    #[target_feature(enable = "avx512f")]
+    unsafe fn shr<const N: u32>(a: V) -> V {
+        _mm512_srli_epi32::<N>(a)
+    }
+
+    /// Rotates right by `N`; AVX-512 has a rotate, so `L` is unused.
+    #[inline]
+    #[target_feature(enable = "avx512f")]
+    unsafe fn rotr<const N: i32, const L: i32>(a: V) -> V {
+        _mm512_ror_epi32::<N>(a)
+    }
+
+    #[inline]
+    #[target_feature(enable = "avx512f")]
+    unsafe fn splat(word: u32) -> V {
+        _mm512_set1_epi32(word as i32)
+    }
+
+    #[inline]
+    #[target_feature(enable = "avx512f")]
+    unsafe fn load(words: &[u32; LANES]) -> V {
+        _mm512_loadu_si512(words.as_ptr() as *const V)
+    }
+
+    #[inline]
+    #[target_feature(enable = "avx512f")]
+    unsafe fn store(v: V) -> [u32; LANES] {
+        let mut words = [0; LANES];
+        _mm512_storeu_si512(words.as_mut_ptr() as *mut V, v);
+        words
+    }
+
+    multiway!("avx512f");
+}
+
+pub mod shani {
+    use super::*;
+    use std::arch::x86_64::*;
+
+    /// Nonces hashed side by side, so the round latency of one hides behind the other.
+    const LANES: usize = 2;
+
+    /// Compresses one big-endian block per lane into the matching `states` entry.
+    #[inline]
+    #[target_feature(enable = "sha,sse2,ssse3,sse4.1")]
+    unsafe fn compress(states: &mut [[u32; 8]; LANES], blocks: [&[u8]; LANES]) {
+        let mask = _mm_set_epi64x(0x0c0d0e0f08090a0b, 0x0405060700010203);
+
+        // state is kept as ABEF / CDGH, the layout sha256rnds2 works on
+        let mut abef = [_mm_setzero_si128(); LANES];
+        let mut cdgh = [_mm_setzero_si128(); LANES];
+        for lane in 0..LANES {
+            let dcba = _mm_loadu_si128(states[lane].as_ptr() as *const __m128i);
+            let hgfe = _mm_loadu_si128(states[lane].as_ptr().add(4) as *const __m128i);
+            let cdab = _mm_shuffle_epi32(dcba, 0xb1);
+            let efgh = _mm_shuffle_epi32(hgfe, 0x1b);
+            abef[lane] = _mm_alignr_epi8(cdab, efgh, 8);
+            cdgh[lane] = _mm_blend_epi16(efgh, cdab, 0xf0);
+        }
+        let (abef_save, cdgh_save) = (abef, cdgh);
+
+        let mut msgs = [[_mm_setzero_si128(); 4]; LANES];
+        for i in 0..16 {
+            let k = _mm_loadu_si128(K.as_ptr().add(i * 4) as *const __m128i);
+
+            for lane in 0..LANES {
+                let msgs = &mut msgs[lane];
+                let msg = if i < 4 {
+                    let words =
+                        _mm_loadu_si128(blocks[lane].as_ptr().add(i * 16) as *const __m128i);
+                    _mm_shuffle_epi8(words, mask)
+                } else {
+                    let (m4, m3) = (msgs[i & 3], msgs[(i + 1) & 3]);
+                    let (m2, m1) = (msgs[(i + 2) & 3], msgs[(i + 3) & 3]);
+                    let w = _mm_add_epi32(_mm_sha256msg1_epu32(m4, m3), _mm_alignr_epi8(m1, m2, 4));
+                    _mm_sha256msg2_epu32(w, m1)
+                };
+                msgs[i & 3] = msg;
+
+                let wk = _mm_add_epi32(msg, k);
+                cdgh[lane] = _mm_sha256rnds2_epu32(cdgh[lane], abef[lane], wk);
+                abef[lane] =
+                    _mm_sha256rnds2_epu32(abef[lane], cdgh[lane], _mm_shuffle_epi32(wk, 0x0e));
+            }
+        }
+
+        for lane in 0..LANES {
+            let abef = _mm_add_epi32(abef[lane], abef_save[lane]);
+            let cdgh = _mm_add_epi32(cdgh[lane], cdgh_save[lane]);
+
+            let feba = _mm_shuffle_epi32(abef, 0x1b);
+            let dchg = _mm_shuffle_epi32(cdgh, 0xb1);
+            let dcba = _mm_blend_epi16(feba, dchg, 0xf0);
+            let hgfe = _mm_alignr_epi8(dchg, feba, 8);
+            _mm_storeu_si128(states[lane].as_mut_ptr() as *mut __m128i, dcba);
+            _mm_storeu_si128(states[lane].as_mut_ptr().add(4) as *mut __m128i, hgfe);
+        }
+    }
+
+    #[target_feature(enable = "sha,sse2,ssse3,sse4.1")]
+    pub unsafe fn sha256d(job: &PreparedJob, nonces: &[[u8; 8]], hashes: &mut [[u8; 32]]) {
+        let mut tails = [job.tail; LANES];
+        for (tail, nonce) in tails.iter_mut().zip(nonces) {
+            tail[job.nonce_offset..job.nonce_offset + 8].copy_from_slice(nonce);
+        }
+
+        let mut states = [job.midstate; LANES];
+        for at in (0..job.tail_len).step_by(64) {
+            compress(
+                &mut states,
+                [&tails[0][at..at + 64], &tails[1][at..at + 64]],
+            );
+        }
+
+        let mut blocks = [[0_u8; 64]; LANES];
+        for (block, state) in blocks.iter_mut().zip(states) {
+            for (chunk, word) in block[..32].chunks_exact_mut(4).zip(state) {
+                chunk.copy_from_slice(&word.to_be_bytes());
+            }
+            block[32] = 0x80;
+            block[62] = 0x01;
+        }
+
+        let mut states = [SHA256_IV; LANES];
+        compress(&mut states, [&blocks[0], &blocks[1]]);
+
+        for (hash, state) in hashes.iter_mut().zip(states) {
+            for (chunk, word) in hash.chunks_exact_mut(4).zip(state) {
+                chunk.copy_from_slice(&word.to_be_bytes());
+            }
+        }
+    }
+}
@@ -0,0 +1,292 @@
+use super::*;
+use std::{
+    collections::HashMap,
+    fs::{File, OpenOptions},
+    io::{BufRead, BufReader, BufWriter, Read, Write},
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tracing::warn;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ShareStatus {
+    /// Found, but the server hasn't confirmed it yet.
+    Pending,
+    Accepted,
+    Rejected,
+}
+
+/// One line of the journal. A share is appended again every time its status changes,
+/// the last line for a given challenge and nonce wins.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct JournalRecord {
+    pub status: ShareStatus,
+    /// Unix time in seconds.
+    pub time: u64,
+    #[serde(flatten)]
+    pub solution: Solution,
+}
+
+/// Append-only JSON lines file of found solutions, so that a share survives a crash
+/// or an API outage between being found and being confirmed.
+#[derive(Debug)]
+pub struct Journal {
+    path: PathBuf,
+    file: std::sync::Mutex<File>,
+}
+
+impl Journal {
+    pub fn open(path: &Path) -> Result<Journal> {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .append(true)
+            .open(path)?;
+
+        // a crash mid-write leaves a torn last line: it is cut off, or ended when it is
+        // a whole record that only lacks its newline, so the next record starts afresh
+        let mut content = vec![];
+        file.read_to_end(&mut content)?;
+        if !content.is_empty() && !content.ends_with(b"\n") {
+            let start = content
+                .iter()
+                .rposition(|&b| b == b'\n')
+                .map_or(0, |at| at + 1);
+            if serde_json::from_slice::<JournalRecord>(&content[start..]).is_ok() {
+                file.write_all(b"\n")?;
+            } else {
+                warn!(
+                    event = "journal_torn_line",
+                    path = %path.display(),
+                    "dropping unreadable last journal line"
+                );
+                file.set_len(start as u64)?;
+            }
+        }
+
+        Ok(Journal {
+            path: path.to_path_buf(),
+            file: std::sync::Mutex::new(file),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn record(&self, solution: &Solution, status: ShareStatus) -> Result<()> {
+        let record = JournalRecord {
+            status,
+            time: SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs(),
+            solution: solution.clone(),
+        };
+
+        let mut line = serde_json::to_string(&record)?;
+        line.push('\n');
+
+        let mut file = self.file.lock().unwrap();
+        file.write_all(line.as_bytes())?;
+        file.flush()?;
+
+        Ok(())
+    }
+
+    /// Flushes the journal to disk, e.g. before exiting.
+    pub fn sync(&self) -> Result<()> {
+        let file = self.file.lock().unwrap();
+        file.sync_all()?;
+
+        Ok(())
+    }
+
+    /// Latest record of every share in the journal at `path`, in the order they were
+    /// first found. A missing file is an empty journal. An unreadable last line, what a
+    /// crash mid-write leaves behind, is skipped with a warning.
+    pub fn load(path: &Path) -> Result<Vec<JournalRecord>> {
+        let file = match File::open(path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut records: Vec<JournalRecord> = vec![];
+        let mut index = HashMap::new();
+        let mut lines = BufReader::new(file).lines().enumerate().peekable();
+        while let Some((i, line)) = lines.next() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            let record: JournalRecord = match serde_json::from_str(&line) {
+                Ok(record) => record,
+                Err(e) if lines.peek().is_none() => {
+                    warn!(
+                        event = "journal_torn_line",
+                        path = %path.display(),
+                        line = i + 1,
+                        error = %e,
+                        "skipping unreadable last journal line"
+                    );
+                    break;
+                }
+                Err(e) => anyhow::bail!("{}:{}: {}", path.display(), i + 1, e),
+            };
+
+            let key = (
+                record.solution.challenge.clone(),
+                record.solution.nonce.clone(),
+            );
+            match index.get(&key) {
+                Some(&at) => records[at] = record,
+                None => {
+                    index.insert(key, records.len());
+                    records.push(record);
+                }
+            }
+        }
+
+        Ok(records)
+    }
+
+    /// Rewrites the journal at `path` with only the latest record of every share,
+    /// leaving out accepted and rejected shares settled more than `keep` ago. Pending
+    /// shares are always kept. Returns how many records are left.
+    pub fn compact(path: &Path, keep: Duration) -> Result<usize> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        let records = Journal::load(path)?
+            .into_iter()
+            .filter(|r| {
+                r.status == ShareStatus::Pending || now.saturating_sub(r.time) <= keep.as_secs()
+            })
+            .collect::<Vec<_>>();
+        if records.is_empty() && !path.exists() {
+            return Ok(0);
+        }
+
+        // written aside and renamed over, so a crash never loses the journal
+        let tmp = PathBuf::from(format!("{}.tmp", path.display()));
+        let mut file = BufWriter::new(File::create(&tmp)?);
+        for record in &records {
+            serde_json::to_writer(&mut file, record)?;
+            file.write_all(b"\n")?;
+        }
+        file.flush()?;
+        file.get_ref().sync_all()?;
+        std::fs::rename(&tmp, path)?;
+
+        Ok(records.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn solution(nonce: &str) -> Solution {
+        Solution {
+            nonce: nonce.to_string(),
+            hash: format!("00000{}", nonce),
+            location: "loc_pepe_0".to_string(),
+            token_id: "id-pepe".to_string(),
+            challenge: vec![0xab; 32],
+        }
+    }
+
+    fn temp_journal(name: &str) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("pow20-{}-{}.jsonl", name, std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn torn_last_line_is_skipped_and_not_appended_to() {
+        let path = temp_journal("torn");
+        let journal = Journal::open(&path).unwrap();
+        journal
+            .record(&solution("01"), ShareStatus::Pending)
+            .unwrap();
+        drop(journal);
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(br#"{"status":"pend"#).unwrap();
+        drop(file);
+
+        let records = Journal::load(&path).unwrap();
+        assert_eq!(records.len(), 1);
+
+        // reopening cuts the torn line off before appending
+        let journal = Journal::open(&path).unwrap();
+        journal
+            .record(&solution("02"), ShareStatus::Pending)
+            .unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap().lines().count(), 2);
+        let nonces = Journal::load(&path)
+            .unwrap()
+            .into_iter()
+            .map(|r| r.solution.nonce)
+            .collect::<Vec<_>>();
+        assert_eq!(nonces, ["01", "02"]);
+        drop(journal);
+
+        // a whole record missing only its newline is kept
+        let content = std::fs::read_to_string(&path).unwrap();
+        std::fs::write(&path, content.trim_end()).unwrap();
+        let journal = Journal::open(&path).unwrap();
+        journal
+            .record(&solution("03"), ShareStatus::Pending)
+            .unwrap();
+        assert_eq!(Journal::load(&path).unwrap().len(), 3);
+
+        // anywhere but last, an unreadable line is an error
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(b"{\"status\"\n").unwrap();
+        journal
+            .record(&solution("04"), ShareStatus::Pending)
+            .unwrap();
+        assert!(Journal::load(&path).is_err());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn compact_keeps_latest_records_and_drops_old_settled_shares() {
+        let path = temp_journal("compact");
+        let journal = Journal::open(&path).unwrap();
+        for (nonce, status) in [
+            ("01", ShareStatus::Pending),
+            ("02", ShareStatus::Pending),
+            ("03", ShareStatus::Pending),
+            ("01", ShareStatus::Accepted),
+            ("02", ShareStatus::Rejected),
+        ] {
+            journal.record(&solution(nonce), status).unwrap();
+        }
+        drop(journal);
+
+        assert_eq!(
+            Journal::compact(&path, Duration::from_secs(3600)).unwrap(),
+            3
+        );
+        assert_eq!(std::fs::read_to_string(&path).unwrap().lines().count(), 3);
+
+        // settled just now, so only older than zero seconds once a second has passed
+        let mut records = Journal::load(&path).unwrap();
+        for record in &mut records {
+            record.time -= 10;
+        }
+        let lines = records
+            .iter()
+            .map(|r| serde_json::to_string(r).unwrap() + "\n")
+            .collect::<String>();
+        std::fs::write(&path, lines).unwrap();
+
+        assert_eq!(Journal::compact(&path, Duration::ZERO).unwrap(), 1);
+        let records = Journal::load(&path).unwrap();
+        assert_eq!(records[0].solution.nonce, "03");
+        assert_eq!(records[0].status, ShareStatus::Pending);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,43 @@
+use anyhow::Result;
+use rand::Rng;
+use serde::*;
+use serde_json::*;
+
+mod affinity;
+pub use affinity::*;
+mod api;
+pub use api::*;
+mod bench;
+pub use bench::*;
+mod config;
+pub use config::*;
+mod hash;
+pub use hash::*;
+mod journal;
+pub use journal::*;
+mod metrics;
+pub use metrics::*;
+mod miner;
+pub use miner::*;
+mod nonce;
+pub use nonce::*;
+mod retry;
+pub use retry::*;
+mod schedule;
+pub use schedule::*;
+mod source;
+pub use source::*;
+mod throttle;
+pub use throttle::*;
+mod verify;
+pub use verify::*;
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Solution {
+    pub nonce: String,
+    pub hash: String,
+    pub location: String,
+    pub token_id: String,
+    #[serde(with = "hex")]
+    pub challenge: Vec<u8>,
+}
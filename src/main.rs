@@ -1,225 +1,1422 @@
 use anyhow::Result;
-use clap::Parser;
-use rand::Rng;
-use rayon::prelude::*;
-use serde::*;
-use serde_json::*;
-use std::{sync::Arc, time::Instant};
-use tokio::sync::Mutex;
-
-mod api;
-pub use api::*;
-mod hash;
-pub use hash::*;
+use arc_swap::ArcSwap;
+use clap::{parser::ValueSource, CommandFactory, FromArgMatches, Parser, Subcommand};
+use pow20miner::*;
+use std::{
+    net::SocketAddr,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicI64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+use tokio::{
+    sync::{mpsc, Mutex},
+    task::JoinSet,
+};
+use tracing::{debug, error, info, warn};
 
 #[derive(Parser, Debug)]
-#[command(author, version, about, long_about = None)]
+#[command(author, version, about, long_about = None, subcommand_negates_reqs = true)]
 #[derive(Clone)]
 struct Args {
-    #[arg(short, long)]
-    tick: String,
-    #[arg(short, long)]
-    address: String,
+    #[command(subcommand)]
+    command: Option<Command>,
+    /// TOML file with defaults for any of these flags, e.g. `threads = 8`; flags and
+    /// environment variables take precedence
+    #[arg(long, global = true, env = "POW20_CONFIG")]
+    config: Option<PathBuf>,
+    /// Ticker to mine as `TICK` or `TICK:WEIGHT`, may be repeated to mine several
+    #[arg(
+        short,
+        long = "tick",
+        value_name = "TICK",
+        required = true,
+        value_delimiter = ','
+    )]
+    ticks: Vec<TickSpec>,
+    #[arg(short, long, required = true)]
+    address: Option<String>,
+    /// Base URL of the pow20 API
+    #[arg(long, env = "POW20_URL", default_value = "http://api.pow20.io")]
+    url: String,
+    /// Value of the `Chain` header sent with every API request
+    #[arg(long, env = "POW20_CHAIN", default_value = "BSV")]
+    chain: String,
+    /// Value of the `Wallet` header sent with every API request
+    #[arg(long, env = "POW20_WALLET", default_value = "PANDA")]
+    wallet: String,
+    /// Extra `Name: value` header sent with every API request, may be repeated; the
+    /// environment variable takes a comma separated list
+    #[arg(long = "header", env = "POW20_HEADERS", value_parser = parse_header)]
+    headers: Vec<(String, String)>,
+    /// Hashing backend (scalar, sha-ni, sse2, avx2, avx512), defaults to the fastest one
+    /// this CPU supports
+    #[arg(long, global = true)]
+    backend: Option<Backend>,
+    /// Hashing threads, defaults to one per CPU in --cpus, or one per CPU
+    #[arg(long, global = true, default_value_t = 0)]
+    threads: usize,
+    /// CPUs to pin the hashing threads to, e.g. `0-3,8`
+    #[arg(long, global = true)]
+    cpus: Option<String>,
+    /// Nice value for the hashing threads, from 0 (unchanged) to 19 (lowest priority)
+    #[arg(long, global = true, default_value_t = 0, value_parser = clap::value_parser!(i32).range(0..=19))]
+    nice: i32,
+    /// Timeout for establishing a connection to the API, in milliseconds
+    #[arg(long, default_value_t = 5_000)]
+    connect_timeout_ms: u64,
+    /// Timeout for a whole API request, in milliseconds
+    #[arg(long, default_value_t = 10_000)]
+    timeout_ms: u64,
+    /// `User-Agent` sent with every API request
+    #[arg(long, default_value = concat!("pow20miner/", env!("CARGO_PKG_VERSION")))]
+    user_agent: String,
+    /// Proxy used for every API request, e.g. `http://127.0.0.1:3128`
+    #[arg(long, env = "POW20_PROXY")]
+    proxy: Option<String>,
+    /// Shortest time between polls of a ticker while no push stream delivers its jobs,
+    /// used after a share or a new job, in milliseconds
+    #[arg(long, default_value_t = 500)]
+    poll_ms: u64,
+    /// Longest time between polls; the interval doubles up to it with every poll that
+    /// finds the job unchanged, in milliseconds
+    #[arg(long, default_value_t = 5_000)]
+    max_poll_ms: u64,
+    /// Server-sent events URL that pushes new jobs, with `{ticker}` for the ticker, e.g.
+    /// `/token/events/bsv?ticker={ticker}`; relative to --url when it starts with `/`.
+    /// Jobs are polled instead while it is unreachable
+    #[arg(long, env = "POW20_PUSH_URL")]
+    push_url: Option<String>,
+    /// After the push stream drops, how long jobs are polled before reconnecting, in
+    /// seconds
+    #[arg(long, default_value_t = 30.0, value_parser = parse_secs)]
+    push_retry_secs: f64,
+    /// How many times a share is resubmitted after a transient failure
+    #[arg(long, default_value_t = 5)]
+    submit_retries: u32,
+    /// Backoff before the first resubmission, doubled on every retry, in milliseconds
+    #[arg(long, default_value_t = 250)]
+    retry_base_ms: u64,
+    /// Upper bound for the resubmission backoff, in milliseconds
+    #[arg(long, default_value_t = 10_000)]
+    retry_max_ms: u64,
+    /// File found solutions are journaled to until the server confirms them
+    #[arg(long, default_value = "pow20-solutions.jsonl")]
+    journal: PathBuf,
+    /// How many days accepted and rejected shares stay in the journal; it is compacted
+    /// on every start, pending shares are always kept
+    #[arg(long, default_value_t = 7)]
+    journal_keep_days: u64,
+    /// How many recent winning hashes are remembered to skip duplicate shares, 0 disables
+    #[arg(long, default_value_t = 10_000)]
+    dedup_window: usize,
+    /// Most shares submitted per second, extra ones are dropped; 0 means unlimited
+    #[arg(long, default_value_t = 10.0)]
+    max_submits_per_sec: f64,
+    /// Worker id put in the first two bytes of every nonce; give every machine mining
+    /// the same ticker a different one so they never try the same nonces
+    #[arg(long, env = "POW20_WORKER_ID", default_value_t = 0)]
+    worker_id: u16,
+    /// Derive the worker id from this string instead, e.g. the host name. There are
+    /// only 65536 ids, so among ~300 seeds two collide about half the time and then
+    /// try the same nonces; use --worker-id for larger fleets
+    #[arg(long, env = "POW20_WORKER_SEED", conflicts_with = "worker_id")]
+    worker_seed: Option<String>,
+    /// File the nonce counters of every challenge are kept in, so that a restart
+    /// doesn't try the same nonces again
+    #[arg(long, default_value = "pow20-nonces.json")]
+    nonce_file: PathBuf,
+    /// How CPU time is split between several tickers
+    #[arg(long, value_enum, default_value_t = Schedule::Weighted)]
+    schedule: Schedule,
+    /// Length of one scheduling round over all tickers, in seconds; with `--schedule
+    /// best`, how often the tickers are ranked
+    #[arg(long, default_value_t = 30.0, value_parser = parse_secs)]
+    cycle_secs: f64,
+    /// How `--schedule best` ranks tickers (reward, difficulty)
+    #[arg(long, default_value_t = Score::Reward)]
+    score: Score,
+    /// With `--schedule best`, how many times the current ticker's score another one
+    /// needs before the miner switches to it
+    #[arg(long, default_value_t = 1.5)]
+    switch_ratio: f64,
+    /// With `--schedule best`, the shortest time a ticker is mined before switching away
+    #[arg(long, default_value_t = 60.0, value_parser = parse_secs)]
+    min_dwell_secs: f64,
+    /// On SIGINT or SIGTERM, how long shares still being submitted get before exiting,
+    /// in seconds; a second signal exits right away
+    #[arg(long, default_value_t = 10.0, value_parser = parse_secs)]
+    shutdown_timeout_secs: f64,
+    /// Serve Prometheus metrics on `http://<ADDR>/metrics`, e.g. `0.0.0.0:9420`
+    #[arg(long, env = "POW20_METRICS_ADDR")]
+    metrics_addr: Option<SocketAddr>,
+    /// Log level or `tracing` filter directives, e.g. `debug` or `pow20miner=debug`
+    #[arg(long, global = true, env = "POW20_LOG", default_value = "info")]
+    log_level: String,
+    /// Log output format
+    #[arg(long, global = true, value_enum, default_value_t = LogFormat::Text)]
+    log_format: LogFormat,
+    /// Append logs to this file instead of writing them to stdout
+    #[arg(long, global = true)]
+    log_file: Option<PathBuf>,
 }
 
-#[derive(Debug)]
-pub struct Solution {
-    pub nonce: String,
-    pub hash: String,
-    pub location: String,
-    pub token_id: String,
-    pub challenge: Vec<u8>,
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq)]
+enum Schedule {
+    /// Time in proportion to each ticker's weight
+    Weighted,
+    /// Time in proportion to each ticker's expected reward per hash, its weight
+    /// divided by 16^difficulty
+    Auto,
+    /// Only the best ranked ticker, see --score
+    Best,
 }
 
-#[derive(Clone, Default)]
+#[derive(clap::ValueEnum, Debug, Clone, Copy)]
+enum LogFormat {
+    Text,
+    /// One JSON object per line
+    Json,
+}
+
+#[derive(Subcommand, Debug, Clone)]
+enum Command {
+    /// List the shares in the solution journal
+    ReplayJournal {
+        /// Submit the pending shares again instead of only listing them
+        #[arg(long)]
+        resubmit: bool,
+    },
+    /// Measure the hashrate offline on a synthetic job
+    Bench(BenchArgs),
+    /// Check a nonce against a challenge, or every vector in a known-answer file
+    Verify(VerifyArgs),
+    /// Print the effective configuration after merging the config file, environment
+    /// and flags
+    PrintConfig,
+}
+
+#[derive(clap::Args, Debug, Clone)]
+struct VerifyArgs {
+    /// Challenge in hex, as served by the API
+    #[arg(long, required_unless_present = "vectors")]
+    challenge: Option<String>,
+    /// 8-byte nonce in hex, as submitted
+    #[arg(long, required_unless_present = "vectors")]
+    nonce: Option<String>,
+    /// Leading zero nibbles the hash needs
+    #[arg(long, required_unless_present = "vectors")]
+    difficulty: Option<i32>,
+    /// File with one `<challenge> <nonce> <difficulty> [<hash>]` vector per line
+    #[arg(long, conflicts_with_all = ["challenge", "nonce", "difficulty"])]
+    vectors: Option<PathBuf>,
+}
+
+#[derive(clap::Args, Debug, Clone)]
+struct BenchArgs {
+    /// Challenge to hash, in hex; derived from --seed when not given
+    #[arg(long)]
+    challenge: Option<String>,
+    /// Seed for the synthetic 32-byte challenge
+    #[arg(long, default_value_t = 0)]
+    seed: u64,
+    /// Leading zero nibbles a hash needs to count as a solution
+    #[arg(long, default_value_t = 6)]
+    difficulty: i32,
+    /// Stop after this many seconds, defaults to 10 unless --hashes is given
+    #[arg(long, value_parser = parse_secs)]
+    duration_secs: Option<f64>,
+    /// Stop after this many hashes
+    #[arg(long)]
+    hashes: Option<u64>,
+    /// How often the hashrate is sampled for the percentiles, in milliseconds
+    #[arg(long, default_value_t = 100)]
+    sample_ms: u64,
+    /// Print the report as JSON
+    #[arg(long)]
+    json: bool,
+}
+
+fn parse_header(s: &str) -> Result<(String, String), String> {
+    match s.split_once(':') {
+        Some((name, value)) if !name.trim().is_empty() => {
+            Ok((name.trim().to_string(), value.trim().to_string()))
+        }
+        _ => Err(format!("expected `Name: value`, got {:?}", s)),
+    }
+}
+
+/// A duration in seconds, which `Duration::from_secs_f64` would panic on unless it is
+/// finite and not negative.
+fn parse_secs(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(secs) if secs >= 0.0 && secs.is_finite() => Ok(secs),
+        Ok(_) => Err(format!(
+            "expected a non-negative number of seconds, got {}",
+            s
+        )),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+/// `POW20_HEADERS`: `Name: value` headers separated by commas.
+fn parse_header_list(s: &str) -> Result<Vec<(String, String)>, String> {
+    s.split(',')
+        .filter(|header| !header.trim().is_empty())
+        .map(parse_header)
+        .collect()
+}
+
+/// Per-ticker counters, bumped from the main loop and the submission tasks without
+/// locking.
+#[derive(Default)]
 pub struct Stats {
+    pub found: AtomicI64,
+    pub submitted: AtomicI64,
+    pub dropped: AtomicI64,
+    pub duplicates: AtomicI64,
+    pub accepted: AtomicI64,
+    pub rejected: AtomicI64,
+    pub retries: AtomicI64,
+    /// Hashes spent on a job after it had already been replaced.
+    pub stale_hashes: AtomicI64,
+}
+
+/// The values of `Stats` at one point in time.
+#[derive(Clone, Default)]
+pub struct StatsSnapshot {
+    pub found: i64,
+    pub submitted: i64,
+    pub dropped: i64,
+    pub duplicates: i64,
     pub accepted: i64,
     pub rejected: i64,
+    pub retries: i64,
+    pub stale_hashes: i64,
+}
+
+impl Stats {
+    fn snapshot(&self) -> StatsSnapshot {
+        let load = |counter: &AtomicI64| counter.load(Ordering::Relaxed);
+
+        StatsSnapshot {
+            found: load(&self.found),
+            submitted: load(&self.submitted),
+            dropped: load(&self.dropped),
+            duplicates: load(&self.duplicates),
+            accepted: load(&self.accepted),
+            rejected: load(&self.rejected),
+            retries: load(&self.retries),
+            stale_hashes: load(&self.stale_hashes),
+        }
+    }
 }
 
 type Address = bitcoin::Address<bitcoin::address::NetworkUnchecked>;
 
+/// What the hashing threads report back to the async side.
+enum Event {
+    Solution(Solution),
+    Bucket(BucketReport),
+}
+
+/// One of the tickers being mined, with its own job, stats and submission path.
+pub struct Job {
+    spec: TickSpec,
+    /// Token id of the ticker, which stays the same across jobs.
+    id: String,
+    /// Latest job of the ticker, replaced as a whole so readers never wait.
+    work: ArcSwap<Ticker>,
+    stats: Stats,
+    /// Time between polls of the ticker, shortened by shares.
+    interval: Arc<AdaptiveInterval>,
+}
+
 #[derive(Clone)]
 pub struct Context {
-    work: Arc<Mutex<Ticker>>,
-    stats: Arc<Mutex<Stats>>,
+    jobs: Arc<Vec<Job>>,
+    /// Index into `jobs` of the ticker the miner is working on.
+    active: Arc<Mutex<usize>>,
+    miner: Arc<Miner>,
+    metrics: Arc<Metrics>,
     api_client: ApiClient,
+    retry: RetryPolicy,
+    journal: Arc<Journal>,
     args: Args,
 }
 
-pub async fn update_work(ctx: &Context) -> () {
-    let mut lock = ctx.work.lock().await;
+impl Context {
+    fn job(&self, solution: &Solution) -> Option<&Job> {
+        self.jobs.iter().find(|job| job.id == solution.token_id)
+    }
+}
+
+/// Points the miner at the latest work of job `index`. Every job handed to the miner
+/// goes through here, under the `active` lock, so a job that changes while another
+/// ticker is being mined can't take over the miner.
+pub async fn activate(ctx: &Context, index: usize) {
+    let mut active = ctx.active.lock().await;
+    *active = index;
+    mine_latest_work(ctx, index);
+}
+
+/// Like `activate`, but only if `job` is still the one being mined, checked under the
+/// same lock so a ticker switch in between can't be undone.
+pub async fn activate_if_current(ctx: &Context, job: &Job) {
+    let active = ctx.active.lock().await;
+    if ctx.jobs[*active].id == job.id {
+        mine_latest_work(ctx, *active);
+    }
+}
+
+/// Hands the latest work of job `index` to the miner. Callers hold the `active` lock.
+fn mine_latest_work(ctx: &Context, index: usize) {
+    let work = ctx.jobs[index].work.load_full();
+    let current = ctx.miner.job();
+    if current.id == work.id
+        && current.challenge == work.challenge
+        && current.difficulty == work.difficulty
+    {
+        return;
+    }
+
+    // validated when the job was fetched
+    let _ = ctx.miner.update_job((*work).clone());
+    ctx.metrics
+        .difficulty
+        .store(work.difficulty as i64, Ordering::Relaxed);
+    debug!(
+        event = "job_activated",
+        ticker = %work.ticker,
+        challenge = %work.challenge,
+        "mining job"
+    );
+}
+
+/// Replaces the job of `job` with `new_work` if it changed, and moves the miner onto it
+/// when `job` is being mined. Readers of the old job are never waited on.
+pub async fn update_work(ctx: &Context, job: &Job, new_work: Result<Ticker>) -> () {
+    let current = job.work.load();
 
-    if let Ok(new_work) = ctx.api_client.fetch_ticker(&ctx.args.tick).await {
-        if lock.challenge != new_work.challenge {
-            *lock = new_work;
-            println!(
-                "new job! ticker: {:?} difficulty: {:?}                                     |\n\n",
-                lock.ticker, lock.difficulty,
+    match new_work {
+        // a new difficulty on the same challenge is a new job too, its target changed
+        Ok(new_work)
+            if current.challenge != new_work.challenge
+                || current.difficulty != new_work.difficulty =>
+        {
+            if let Err(e) = challenge_bytes(&new_work) {
+                warn!(event = "job_invalid", ticker = %job.spec.tick, challenge = %new_work.challenge, error = %e, "invalid job");
+                return;
+            }
+            ctx.metrics.job_fetched();
+            ctx.metrics.job_changes.fetch_add(1, Ordering::Relaxed);
+            info!(
+                event = "job_changed",
+                ticker = %new_work.ticker,
+                challenge = %new_work.challenge,
+                difficulty = new_work.difficulty,
+                "new job"
             );
+            job.work.store(Arc::new(new_work));
+            activate_if_current(ctx, job).await;
+        }
+        Ok(_) => ctx.metrics.job_fetched(),
+        Err(e) => {
+            debug!(event = "job_fetch_failed", ticker = %job.spec.tick, error = %e, "failed to fetch job")
         }
     }
-    drop(lock);
+}
+
+/// Whether `solution` was found for the current job of its ticker.
+pub fn is_current(solution: &Solution, job: &Job) -> bool {
+    challenge_bytes(&job.work.load()).unwrap_or_default() == solution.challenge
+}
+
+fn journal_record(journal: &Journal, solution: &Solution, status: ShareStatus) {
+    if let Err(e) = journal.record(solution, status) {
+        error!(
+            event = "journal_write_failed",
+            path = %journal.path().display(),
+            error = %e,
+            "failed to write journal"
+        );
+    }
+}
+
+/// The challenge of the job `solution` was found for, in the API's byte order.
+fn job_challenge(solution: &Solution) -> String {
+    let mut challenge = solution.challenge.clone();
+    challenge.reverse();
+    hex::encode(challenge)
+}
+
+/// Sends `solution` off to the API, unless it was submitted before or the submit rate
+/// limit is reached; a share over the limit is journaled as pending. Shares found now
+/// and pending shares resubmitted on start both go through here.
+fn send_share(
+    ctx: &Context,
+    job: &Job,
+    solution: Solution,
+    dedup: &mut Dedup,
+    limiter: &mut RateLimiter,
+    submissions: &mut JoinSet<()>,
+) {
+    if !dedup.insert(&solution.hash) {
+        job.stats.duplicates.fetch_add(1, Ordering::Relaxed);
+        debug!(event = "share_duplicate", hash = %solution.hash, "skipping duplicate share");
+    } else if !limiter.try_acquire() {
+        job.stats.dropped.fetch_add(1, Ordering::Relaxed);
+        // kept pending, `replay-journal --resubmit` or the next start sends it
+        journal_record(&ctx.journal, &solution, ShareStatus::Pending);
+        warn!(event = "share_dropped", hash = %solution.hash, "submit rate limit reached, journaling share as pending");
+    } else {
+        job.stats.submitted.fetch_add(1, Ordering::Relaxed);
+
+        let cloned = ctx.clone();
+        submissions.spawn(async move {
+            submit_work(&solution, &cloned).await;
+        });
+    }
 }
 
 pub async fn submit_work(solution: &Solution, ctx: &Context) -> () {
-    let submit_res = ctx.api_client.submit_share(solution).await;
+    let Some(job) = ctx.job(solution) else {
+        warn!(
+            event = "share_unknown",
+            token_id = %solution.token_id,
+            nonce = %solution.nonce,
+            "share for a ticker that isn't being mined"
+        );
+        return;
+    };
+
+    journal_record(&ctx.journal, solution, ShareStatus::Pending);
+
+    let start_time = Instant::now();
+    let mut attempt = 0;
+    let submit_res = loop {
+        let res = ctx.api_client.submit_share(solution).await;
+        let Err(e) = &res else {
+            break res;
+        };
+
+        if attempt >= ctx.retry.max_retries || !e.is_transient() {
+            break res;
+        }
+
+        if !is_current(solution, job) {
+            info!(
+                event = "retry_abandoned",
+                ticker = %job.spec.tick,
+                challenge = %job_challenge(solution),
+                nonce = %solution.nonce,
+                "challenge changed, not retrying share"
+            );
+            break res;
+        }
+
+        let mut delay = ctx.retry.delay(attempt);
+        if let ApiError::RateLimited {
+            retry_after: Some(retry_after),
+            ..
+        } = e
+        {
+            delay = delay.max(*retry_after);
+        }
+        attempt += 1;
+        job.stats.retries.fetch_add(1, Ordering::Relaxed);
+
+        warn!(
+            event = "submit_retry",
+            ticker = %job.spec.tick,
+            challenge = %job_challenge(solution),
+            nonce = %solution.nonce,
+            attempt,
+            max_retries = ctx.retry.max_retries,
+            delay_ms = delay.as_millis() as u64,
+            error = %e,
+            "submit failed, retrying"
+        );
+        tokio::time::sleep(delay).await;
+    };
+    let latency = start_time.elapsed();
+    let latency_ms = latency.as_millis() as u64;
+    ctx.metrics.submit_latency.observe(latency);
+
+    let accepted = || {
+        job.stats.accepted.fetch_add(1, Ordering::Relaxed);
+        ctx.metrics.shares_accepted.fetch_add(1, Ordering::Relaxed);
+        journal_record(&ctx.journal, solution, ShareStatus::Accepted);
+    };
+    let rejected = |status| {
+        job.stats.rejected.fetch_add(1, Ordering::Relaxed);
+        ctx.metrics.shares_rejected.fetch_add(1, Ordering::Relaxed);
+        journal_record(&ctx.journal, solution, status);
+    };
+
+    match &submit_res {
+        Ok(_) => {
+            accepted();
+            info!(
+                event = "share_accepted",
+                ticker = %job.spec.tick,
+                challenge = %job_challenge(solution),
+                nonce = %solution.nonce,
+                hash = %solution.hash,
+                location = %solution.location,
+                latency_ms,
+                "accepted share"
+            );
+        }
+        // after a retry this is an earlier attempt that got through without its answer
+        Err(ApiError::Duplicate(e)) if attempt > 0 => {
+            accepted();
+            info!(
+                event = "share_accepted",
+                ticker = %job.spec.tick,
+                challenge = %job_challenge(solution),
+                nonce = %solution.nonce,
+                hash = %solution.hash,
+                location = %solution.location,
+                status = e.status,
+                response = %e.message,
+                attempts = attempt + 1,
+                latency_ms,
+                "accepted share on an earlier attempt"
+            );
+        }
+        Err(ApiError::Duplicate(e)) => {
+            job.stats.duplicates.fetch_add(1, Ordering::Relaxed);
+            rejected(ShareStatus::Rejected);
+            info!(
+                event = "share_duplicate",
+                ticker = %job.spec.tick,
+                challenge = %job_challenge(solution),
+                nonce = %solution.nonce,
+                hash = %solution.hash,
+                status = e.status,
+                response = %e.message,
+                attempts = attempt + 1,
+                "share was already submitted"
+            );
+        }
+        Err(ApiError::StaleChallenge(e)) => {
+            rejected(ShareStatus::Rejected);
+            warn!(
+                event = "share_stale",
+                ticker = %job.spec.tick,
+                challenge = %job_challenge(solution),
+                nonce = %solution.nonce,
+                status = e.status,
+                response = %e.message,
+                latency_ms,
+                "share is for an old challenge, refetching job"
+            );
+        }
+        Err(ApiError::InvalidNonce(e)) => {
+            rejected(ShareStatus::Rejected);
+            error!(
+                event = "share_invalid",
+                ticker = %job.spec.tick,
+                challenge = %job_challenge(solution),
+                nonce = %solution.nonce,
+                hash = %solution.hash,
+                status = e.status,
+                response = %e.message,
+                "server says the nonce doesn't solve the challenge"
+            );
+        }
+        // a share that failed transiently stays pending so it can be replayed
+        Err(e) if e.is_transient() => {
+            ctx.metrics.shares_errored.fetch_add(1, Ordering::Relaxed);
+            error!(
+                event = "share_error",
+                ticker = %job.spec.tick,
+                challenge = %job_challenge(solution),
+                nonce = %solution.nonce,
+                hash = %solution.hash,
+                error = %e,
+                attempts = attempt + 1,
+                latency_ms,
+                "failed to submit share"
+            );
+        }
+        Err(e) => {
+            rejected(ShareStatus::Rejected);
+            warn!(
+                event = "share_rejected",
+                ticker = %job.spec.tick,
+                challenge = %job_challenge(solution),
+                nonce = %solution.nonce,
+                hash = %solution.hash,
+                error = %e,
+                latency_ms,
+                "rejected share"
+            );
+        }
+    }
+
+    // no point polling for a new job while shutting down
+    if ctx.miner.is_running() {
+        match submit_res {
+            Err(ApiError::StaleChallenge(_)) => job.interval.poll_now(),
+            _ => job.interval.share_found(),
+        }
+    }
+}
+
+impl Args {
+    fn cpus(&self) -> Result<Vec<usize>> {
+        match &self.cpus {
+            Some(cpus) => parse_cpu_list(cpus),
+            None => Ok(vec![]),
+        }
+    }
+
+    /// One thread per pinned CPU unless --threads says otherwise, 0 lets the miner
+    /// start one per CPU.
+    fn threads(&self) -> Result<usize> {
+        if self.threads == 0 {
+            return Ok(self.cpus()?.len());
+        }
+
+        Ok(self.threads)
+    }
+
+    fn init_logging(&self) -> Result<()> {
+        use std::io::IsTerminal;
+        use tracing_subscriber::fmt::writer::BoxMakeWriter;
+
+        let (writer, ansi) = match &self.log_file {
+            Some(path) => {
+                let file = std::fs::OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)?;
+                (BoxMakeWriter::new(std::sync::Mutex::new(file)), false)
+            }
+            None => (
+                BoxMakeWriter::new(std::io::stdout),
+                std::io::stdout().is_terminal(),
+            ),
+        };
+
+        let builder = tracing_subscriber::fmt()
+            .with_env_filter(tracing_subscriber::EnvFilter::try_new(&self.log_level)?)
+            .with_ansi(ansi)
+            .with_writer(writer);
+        match self.log_format {
+            LogFormat::Text => builder.init(),
+            LogFormat::Json => builder.json().init(),
+        }
+
+        Ok(())
+    }
+
+    /// Polling, or the push stream with polling as its fallback when --push-url is set.
+    fn job_source(&self, api_client: &ApiClient, job: &Job) -> Box<dyn JobSource> {
+        let tick = &job.spec.tick;
+        let poll = Box::new(Polling::new(
+            api_client.clone(),
+            tick.to_string(),
+            job.interval.clone(),
+        ));
+        let Some(push_url) = &self.push_url else {
+            return poll;
+        };
+
+        let mut url = push_url.replace("{ticker}", tick);
+        if url.starts_with('/') {
+            url = format!("{}{}", self.url, url);
+        }
+        let push = Box::new(EventStream::new(api_client.clone(), url));
+
+        Box::new(Fallback::new(
+            tick.to_string(),
+            push,
+            poll,
+            Duration::from_secs_f64(self.push_retry_secs),
+        ))
+    }
+
+    fn api_client(&self) -> Result<ApiClient> {
+        let address = match &self.address {
+            Some(address) if address.parse::<Address>().is_ok() => address,
+            Some(address) => anyhow::bail!("failed to parse address: {}", address),
+            None => anyhow::bail!("--address is required"),
+        };
+
+        let mut http = reqwest::Client::builder()
+            .connect_timeout(Duration::from_millis(self.connect_timeout_ms))
+            .timeout(Duration::from_millis(self.timeout_ms))
+            .user_agent(self.user_agent.clone());
+        if let Some(proxy) = &self.proxy {
+            http = http.proxy(reqwest::Proxy::all(proxy)?);
+        }
+
+        Ok(ApiClient {
+            url: self.url.clone(),
+            address: address.to_string(),
+            chain: self.chain.clone(),
+            wallet: self.wallet.clone(),
+            headers: self.headers.clone(),
+            client: http.build()?,
+        })
+    }
+}
+
+/// The first 4 bytes of `challenge` in hex, or all of it when it is shorter.
+fn short_challenge(challenge: &[u8]) -> String {
+    hex::encode(challenge.get(..4).unwrap_or(challenge))
+}
+
+async fn replay_journal(args: &Args, resubmit: bool) -> Result<()> {
+    let records = Journal::load(&args.journal)?;
+    if records.is_empty() {
+        println!("{} has no shares", args.journal.display());
+        return Ok(());
+    }
+
+    for record in &records {
+        println!(
+            "{:<8} {} [{}] nonce: {} hash: {} token: {} location: {}",
+            format!("{:?}", record.status).to_lowercase(),
+            record.time,
+            short_challenge(&record.solution.challenge),
+            record.solution.nonce,
+            record.solution.hash,
+            record.solution.token_id,
+            record.solution.location,
+        );
+    }
 
+    if !resubmit {
+        return Ok(());
+    }
+
+    let api_client = args.api_client()?;
+    let journal = Journal::open(&args.journal)?;
+
+    for record in records.iter().filter(|r| r.status == ShareStatus::Pending) {
+        let solution = &record.solution;
+        match api_client.submit_share(solution).await {
+            Ok(_) => {
+                println!(
+                    "[{}] {} ✅ accepted share",
+                    short_challenge(&solution.challenge),
+                    solution.nonce
+                );
+                journal.record(solution, ShareStatus::Accepted)?;
+            }
+            Err(e) if e.is_transient() => println!(
+                "[{}] {} ❌ submit failed: {}",
+                short_challenge(&solution.challenge),
+                solution.nonce,
+                e
+            ),
+            Err(e) => {
+                println!(
+                    "[{}] {} ❌ rejected share: {}",
+                    short_challenge(&solution.challenge),
+                    solution.nonce,
+                    e
+                );
+                journal.record(solution, ShareStatus::Rejected)?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn bench(args: &Args, bench_args: &BenchArgs) -> Result<()> {
+    let mut job = BenchConfig::synthetic_job(bench_args.seed, 32, bench_args.difficulty);
+    if let Some(challenge) = &bench_args.challenge {
+        job.challenge = challenge.clone();
+    }
+
+    let duration = match (bench_args.duration_secs, bench_args.hashes) {
+        (None, None) => Some(10.0),
+        (duration_secs, _) => duration_secs,
+    };
+
+    let config = BenchConfig {
+        job,
+        threads: args.threads()?,
+        backend: args.backend.unwrap_or_else(Backend::detect),
+        cpus: args.cpus()?,
+        nice: args.nice,
+        duration: duration.map(Duration::from_secs_f64),
+        hashes: bench_args.hashes,
+        sample_interval: Duration::from_millis(bench_args.sample_ms),
+    };
+    let report = config.run()?;
+
+    if bench_args.json {
+        println!("{}", serde_json::to_string_pretty(&report)?);
+        return Ok(());
+    }
+
+    println!(
+        "backend: {} threads: {} difficulty: {}",
+        report.backend, report.threads, report.difficulty
+    );
     println!(
-        "[{}] found solution! submitting... submit solution\n\tnonce: {:?}\n\thash: {:?}\n\tlocation: {:?}\n\tchallenge: {:?}                                     \n\n",
-        hex::encode(&solution.challenge[0..4]),
-        solution.nonce,
-        solution.hash,
-        solution.location,
-        hex::encode(&solution.challenge)
+        "hashes: {} solutions: {} in {:.2}s",
+        report.hashes, report.solutions, report.elapsed_secs
     );
+    println!(
+        "mean: {:.2} MH/s p50: {:.2} MH/s p99: {:.2} MH/s",
+        report.mean / 1_000_000.0,
+        report.p50 / 1_000_000.0,
+        report.p99 / 1_000_000.0
+    );
+    for (i, hashrate) in report.per_thread.iter().enumerate() {
+        println!("thread {}: {:.2} MH/s", i, hashrate / 1_000_000.0);
+    }
+
+    Ok(())
+}
+
+fn verify(verify_args: &VerifyArgs) -> Result<()> {
+    let Some(path) = &verify_args.vectors else {
+        let verification = verify_nonce(
+            verify_args.challenge.as_ref().unwrap(),
+            verify_args.nonce.as_ref().unwrap(),
+            verify_args.difficulty.unwrap(),
+        )?;
 
-    if let Ok((status_code, response)) = &submit_res {
-        let mut stats_lock = ctx.stats.lock().await;
+        println!("hash: {}", hex::encode(verification.hash));
+        println!("leading zeros: {}", verification.leading_zeros);
+        println!(
+            "difficulty {}: {}",
+            verification.difficulty,
+            if verification.passed { "pass" } else { "fail" }
+        );
 
-        if status_code.clone() == 201 {
-            stats_lock.accepted = stats_lock.accepted + 1;
-            println!(
-                "[{}] ✅ accepted share                                     \n\n",
-                hex::encode(&solution.challenge[0..4])
-            )
-        } else {
-            stats_lock.rejected = stats_lock.rejected + 1;
+        if !verification.passed {
+            std::process::exit(1);
+        }
+        return Ok(());
+    };
 
-            println!(
-                "[{}] ❌ rejected share {:?}                                     \n\n",
-                hex::encode(&solution.challenge[0..4]),
-                response
-            )
+    let vectors = KnownAnswer::load(path)?;
+    let mut failed = 0;
+    for vector in &vectors {
+        let (verification, hash_matches) = vector.check()?;
+        let ok = verification.passed && hash_matches;
+        if !ok {
+            failed += 1;
         }
 
-        drop(stats_lock)
+        println!(
+            "{}:{} {} hash: {} leading zeros: {} difficulty: {}{}",
+            path.display(),
+            vector.line,
+            if ok { "pass" } else { "fail" },
+            hex::encode(verification.hash),
+            verification.leading_zeros,
+            verification.difficulty,
+            if hash_matches { "" } else { " (hash mismatch)" }
+        );
     }
 
-    if let Err(r) = submit_res {
-        println!("❌ reject share: {}                                     \n\n", r)
+    println!(
+        "{} of {} vectors passed",
+        vectors.len() - failed,
+        vectors.len()
+    );
+    if failed > 0 {
+        std::process::exit(1);
     }
 
-    update_work(ctx).await;
+    Ok(())
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    let args = Args::parse();
-    let num_threads = 2 * num_cpus::get();
+    let argv = std::env::args_os().collect::<Vec<_>>();
+    let mut command = Args::command();
+    let config = match Config::find(&argv, "POW20_CONFIG") {
+        Some(path) => Some(Config::load(&path, &command)?),
+        None => None,
+    };
+    if let Some(config) = &config {
+        command = config.apply(command);
+    }
 
+    let matches = command.clone().get_matches_from(argv);
+    let mut args = Args::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+    // only the environment variable is a list, a `--header` value may contain commas
+    if matches.value_source("headers") == Some(ValueSource::EnvVariable) {
+        let list = std::env::var("POW20_HEADERS").unwrap_or_default();
+        args.headers = parse_header_list(&list).unwrap_or_else(|e| {
+            Args::command()
+                .error(
+                    clap::error::ErrorKind::ValueValidation,
+                    format!("invalid POW20_HEADERS: {}", e),
+                )
+                .exit()
+        });
+    }
 
-    if let Err(_) = args.address.parse::<Address>() {
-        println!("failed to parse address: {}                                     \n\n", args.address);
+    if let Some(Command::PrintConfig) = &args.command {
+        print!(
+            "{}",
+            Config::render(&Args::command(), &matches, config.as_ref())
+        );
         return Ok(());
     }
 
-    let api_client = ApiClient {
-        url: "http://api.pow20.io".to_string(),
-        address: args.address.to_string(),
-    };
+    args.init_logging()?;
 
-    let token = match api_client.fetch_ticker(&args.tick).await {
-        Ok(v) => v,
-        Err(e) => {
-            println!("failed to fetch tick: {:?}                                     \n\n", args.tick);
-            println!("{:?}                                     |\n\n", e);
-            return Ok(());
+    match &args.command {
+        Some(Command::ReplayJournal { resubmit }) => replay_journal(&args, *resubmit).await,
+        Some(Command::Bench(bench_args)) => bench(&args, bench_args),
+        Some(Command::Verify(verify_args)) => verify(verify_args),
+        Some(Command::PrintConfig) => Ok(()),
+        None => mine(args).await,
+    }
+}
+
+async fn mine(args: Args) -> Result<()> {
+    let api_client = args.api_client()?;
+
+    let backend = args.backend.unwrap_or_else(Backend::detect);
+    if !backend.is_supported() {
+        anyhow::bail!("backend {} is not supported by this CPU", backend);
+    }
+
+    if args.ticks.len() > 1 && args.cycle_secs <= 0.0 {
+        anyhow::bail!("--cycle-secs must be positive");
+    }
+
+    let mut jobs: Vec<Job> = vec![];
+    for spec in &args.ticks {
+        if jobs.iter().any(|job| job.spec.tick == spec.tick) {
+            anyhow::bail!("ticker {} given more than once", spec.tick);
         }
-    };
 
-    let work = Arc::new(Mutex::new(token.clone()));
+        let token = match api_client.fetch_ticker(&spec.tick).await {
+            Ok(v) => v,
+            Err(e) => {
+                error!(event = "job_fetch_failed", ticker = %spec.tick, error = ?e, "failed to fetch tick");
+                return Ok(());
+            }
+        };
+        challenge_bytes(&token)?;
+
+        jobs.push(Job {
+            spec: spec.clone(),
+            id: token.id.clone(),
+            work: ArcSwap::from_pointee(token),
+            stats: Stats::default(),
+            interval: Arc::new(AdaptiveInterval::new(
+                Duration::from_millis(args.poll_ms),
+                Duration::from_millis(args.max_poll_ms),
+            )),
+        });
+    }
+    let token = (*jobs[0].work.load_full()).clone();
+
+    let nonces = match &args.worker_seed {
+        Some(seed) => NonceSpace::from_seed(seed),
+        None => NonceSpace::new(args.worker_id),
+    }
+    .with_file(&args.nonce_file)?;
+    let worker_id = nonces.worker_id();
+
+    let (events, mut rx) = mpsc::unbounded_channel();
+    let solutions = events.clone();
+    let miner = Miner::new(token.clone(), args.threads()?, move |solution| {
+        let _ = solutions.send(Event::Solution(solution));
+    })?
+    .backend(backend)
+    .cpus(args.cpus()?)
+    .nice(args.nice)
+    .nonce_space(nonces)
+    .on_bucket(move |report| {
+        let _ = events.send(Event::Bucket(report.clone()));
+    });
+
+    let kept = Journal::compact(
+        &args.journal,
+        Duration::from_secs(args.journal_keep_days.saturating_mul(24 * 60 * 60)),
+    )?;
+    debug!(event = "journal_compacted", path = %args.journal.display(), records = kept, "compacted journal");
 
     let ctx = Context {
-        work,
-        stats: Arc::new(Mutex::new(Stats::default())),
+        jobs: Arc::new(jobs),
+        active: Arc::new(Mutex::new(0)),
+        miner: Arc::new(miner),
+        metrics: Arc::new(Metrics::default()),
         api_client: api_client.clone(),
+        retry: RetryPolicy {
+            max_retries: args.submit_retries,
+            base_delay: Duration::from_millis(args.retry_base_ms),
+            max_delay: Duration::from_millis(args.retry_max_ms),
+        },
+        journal: Arc::new(Journal::open(&args.journal)?),
         args: args.clone(),
     };
 
-    print!(
-        "\nnew job! ticker: {:?} difficulty: {:?}                        \n\n",
-        token.ticker, token.difficulty
-    );
+    for job in ctx.jobs.iter() {
+        let work = job.work.load();
+        info!(
+            event = "job_changed",
+            ticker = %work.ticker,
+            challenge = %work.challenge,
+            difficulty = work.difficulty,
+            weight = job.spec.weight,
+            backend = %backend,
+            threads = ctx.miner.thread_hashes().len(),
+            worker_id,
+            "new job"
+        );
+    }
+
+    ctx.metrics.job_fetched();
+    ctx.metrics
+        .difficulty
+        .store(token.difficulty as i64, Ordering::Relaxed);
+    if let Some(addr) = args.metrics_addr {
+        let metrics = ctx.metrics.clone();
+        tokio::spawn(async move {
+            if let Err(e) = metrics.serve(addr).await {
+                error!(event = "metrics_failed", addr = %addr, error = %e, "metrics endpoint failed");
+            }
+        });
+    }
 
-    let cloned = ctx.clone();
-    tokio::spawn(async move {
-        loop {
-            tokio::time::sleep(tokio::time::Duration::from_millis(500)).await;
-            update_work(&cloned).await;
+    let mut current_challenges = vec![];
+    for job in ctx.jobs.iter() {
+        current_challenges.push(challenge_bytes(&job.work.load())?);
+    }
+    let pending = Journal::load(&args.journal)?
+        .into_iter()
+        .filter(|r| {
+            r.status == ShareStatus::Pending && current_challenges.contains(&r.solution.challenge)
+        })
+        .collect::<Vec<_>>();
+    if !pending.is_empty() {
+        info!(
+            event = "journal_resubmit",
+            shares = pending.len(),
+            path = %args.journal.display(),
+            "resubmitting pending shares"
+        );
+    }
+    let mut dedup = Dedup::new(args.dedup_window);
+    let mut limiter = RateLimiter::new(args.max_submits_per_sec);
+    let mut submissions = JoinSet::new();
+    for record in pending {
+        if let Some(job) = ctx.job(&record.solution) {
+            send_share(
+                &ctx,
+                job,
+                record.solution,
+                &mut dedup,
+                &mut limiter,
+                &mut submissions,
+            );
         }
-    });
+    }
+
+    for index in 0..ctx.jobs.len() {
+        let cloned = ctx.clone();
+        let mut source = args.job_source(&api_client, &ctx.jobs[index]);
+        tokio::spawn(async move {
+            let job = &cloned.jobs[index];
+            loop {
+                let new_work = source.next_job().await;
+                update_work(&cloned, job, new_work).await;
+            }
+        });
+    }
+
+    if ctx.jobs.len() > 1 {
+        let cloned = ctx.clone();
+        let cycle = Duration::from_secs_f64(args.cycle_secs);
+        tokio::spawn(async move {
+            match cloned.args.schedule {
+                Schedule::Best => switch_to_best(&cloned, cycle).await,
+                _ => time_slice(&cloned, cycle).await,
+            }
+        });
+    }
+
+    let started = Instant::now();
+    ctx.miner.start()?;
+
+    let signal = shutdown_signal();
+    tokio::pin!(signal);
 
-    let mut nonce: u16 = 1;
-    let bucket_size:u32 = 1_000_000;
-    let bucket = (0..bucket_size).collect::<Vec<u32>>();
     loop {
-        let start_time = Instant::now();
-
-        let work_lock = ctx.work.lock().await;
-        let work = work_lock.clone();
-        drop(work_lock);
-
-        let mut challenge_bytes = hex::decode(work.challenge.clone()).unwrap();
-        challenge_bytes.reverse();
-        
-        let results = bucket
-            .par_iter()
-            .map(|prefix| {
-                let random = rand::thread_rng().gen::<[u8; 4]>();
-
-                let mut data = [0; 8];
-                data[..4].copy_from_slice(&prefix.to_le_bytes());
-                data[4..].copy_from_slice(&random);
-
-                let mut preimage = [0_u8; 64];
-                preimage[..challenge_bytes.len()].copy_from_slice(&challenge_bytes);
-                preimage[challenge_bytes.len()..challenge_bytes.len() + 8].copy_from_slice(&data);
-
-                let solution = Hash::sha256d(&preimage[..challenge_bytes.len() + 8]);
-
-                for i in 0..work.difficulty {
-                    let rshift = (1 - (i % 2)) << 2;
-                    if (solution[(i / 2) as usize] >> rshift) & 0x0f != 0 {
-                        return None;
-                    }
-                }
-
-                return Some(Solution {
-                    nonce: hex::encode(data),
-                    hash: hex::encode(solution),
-                    location: work.current_location.clone(),
-                    token_id: work.id.clone(),
-                    challenge: challenge_bytes.clone(),
-                });
-            })
-            .filter_map(|e| match e {
-                Some(e) => Some(e),
-                None => None,
-            })
-            .collect::<Vec<_>>();
-
-        let duration = start_time.elapsed().as_micros();
-        let stats_lock = ctx.stats.lock().await;
-        let stats = stats_lock.clone();
-        drop(stats_lock);
-        
-        print!(
-            "[{}] diff: {} accepted: {} rejected: {} hash: {:.2} MH/s                               \n",
-            hex::encode(&challenge_bytes[0..4]),
-            work.difficulty,
-            stats.accepted,
-            stats.rejected,
-            bucket.len() as f64 / 1000.0 / ((duration as f64) / 1000.0)
+        let event = tokio::select! {
+            event = rx.recv() => match event {
+                Some(event) => event,
+                None => break,
+            },
+            Some(_) = submissions.join_next(), if !submissions.is_empty() => continue,
+            _ = &mut signal => break,
+        };
+
+        match event {
+            Event::Solution(solution) => {
+                let Some(job) = ctx.job(&solution) else {
+                    continue;
+                };
+
+                job.stats.found.fetch_add(1, Ordering::Relaxed);
+                ctx.metrics.shares_found.fetch_add(1, Ordering::Relaxed);
+                info!(
+                    event = "share_found",
+                    ticker = %job.spec.tick,
+                    challenge = %job_challenge(&solution),
+                    nonce = %solution.nonce,
+                    hash = %solution.hash,
+                    "found solution"
+                );
+                send_share(
+                    &ctx,
+                    job,
+                    solution,
+                    &mut dedup,
+                    &mut limiter,
+                    &mut submissions,
+                );
+            }
+            Event::Bucket(report) => {
+                ctx.metrics
+                    .hashes
+                    .fetch_add(report.hashes, Ordering::Relaxed);
+                ctx.metrics.set_hashrate(report.hashrate());
+
+                let Some(job) = ctx.jobs.iter().find(|job| job.id == report.job.id) else {
+                    continue;
+                };
+                job.stats
+                    .stale_hashes
+                    .fetch_add(report.stale_hashes as i64, Ordering::Relaxed);
+                let stats = job.stats.snapshot();
+
+                info!(
+                    event = "hashrate",
+                    ticker = %job.spec.tick,
+                    challenge = %report.job.challenge,
+                    difficulty = report.job.difficulty,
+                    hashrate_mhs = report.hashrate() / 1_000_000.0,
+                    found = stats.found,
+                    submitted = stats.submitted,
+                    dropped = stats.dropped,
+                    duplicates = stats.duplicates,
+                    accepted = stats.accepted,
+                    rejected = stats.rejected,
+                    retries = stats.retries,
+                    stale_hashes = stats.stale_hashes,
+                    "{:.2} MH/s",
+                    report.hashrate() / 1_000_000.0
+                );
+            }
+        }
+    }
+
+    shutdown(&ctx, rx, submissions, started).await
+}
+
+/// Resolves on the first SIGINT or SIGTERM.
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        if let Ok(mut terminate) = signal(SignalKind::terminate()) {
+            tokio::select! {
+                _ = tokio::signal::ctrl_c() => {}
+                _ = terminate.recv() => {}
+            }
+            return;
+        }
+    }
+
+    let _ = tokio::signal::ctrl_c().await;
+}
+
+/// Stops hashing, journals the solutions that were found but not submitted yet, gives
+/// the submissions in flight up to --shutdown-timeout-secs and logs a summary.
+async fn shutdown(
+    ctx: &Context,
+    mut rx: mpsc::UnboundedReceiver<Event>,
+    mut submissions: JoinSet<()>,
+    started: Instant,
+) -> Result<()> {
+    info!(
+        event = "shutdown",
+        in_flight = submissions.len(),
+        "shutting down"
+    );
+
+    let miner = ctx.miner.clone();
+    tokio::task::spawn_blocking(move || miner.stop()).await?;
+    let runtime = started.elapsed();
+
+    // left pending in the journal, `replay-journal --resubmit` picks them up
+    while let Ok(event) = rx.try_recv() {
+        if let Event::Solution(solution) = event {
+            if let Some(job) = ctx.job(&solution) {
+                job.stats.found.fetch_add(1, Ordering::Relaxed);
+                ctx.metrics.shares_found.fetch_add(1, Ordering::Relaxed);
+                journal_record(&ctx.journal, &solution, ShareStatus::Pending);
+            }
+        }
+    }
+
+    let timeout = Duration::from_secs_f64(ctx.args.shutdown_timeout_secs);
+    let drained = tokio::select! {
+        res = tokio::time::timeout(timeout, async {
+            while submissions.join_next().await.is_some() {}
+        }) => res.is_ok(),
+        _ = shutdown_signal() => false,
+    };
+    if !drained {
+        warn!(
+            event = "shutdown_timeout",
+            abandoned = submissions.len(),
+            "gave up on shares still being submitted, they stay pending in the journal"
         );
+        submissions.abort_all();
+    }
+
+    for coverage in ctx.miner.coverage() {
+        let mut challenge = coverage.challenge.clone();
+        challenge.reverse();
+        info!(
+            event = "nonce_coverage",
+            challenge = %hex::encode(challenge),
+            worker_id = coverage.worker_id,
+            nonces = coverage.assigned,
+            "nonces 0..{} of worker {} handed out",
+            coverage.assigned,
+            coverage.worker_id
+        );
+    }
+
+    if let Err(e) = ctx.journal.sync() {
+        error!(
+            event = "journal_write_failed",
+            path = %ctx.journal.path().display(),
+            error = %e,
+            "failed to flush journal"
+        );
+    }
+
+    let mut total = StatsSnapshot::default();
+    for job in ctx.jobs.iter() {
+        let stats = job.stats.snapshot();
+        if ctx.jobs.len() > 1 {
+            info!(
+                event = "summary",
+                ticker = %job.spec.tick,
+                found = stats.found,
+                accepted = stats.accepted,
+                rejected = stats.rejected,
+                "{}: found {} accepted {} rejected {}",
+                job.spec.tick,
+                stats.found,
+                stats.accepted,
+                stats.rejected
+            );
+        }
+
+        total.found += stats.found;
+        total.accepted += stats.accepted;
+        total.rejected += stats.rejected;
+    }
+
+    let hashes = ctx.miner.hashes();
+    let hashrate = hashes as f64 / runtime.as_secs_f64();
+    info!(
+        event = "summary",
+        runtime_secs = runtime.as_secs_f64(),
+        hashes,
+        hashrate_mhs = hashrate / 1_000_000.0,
+        found = total.found,
+        accepted = total.accepted,
+        rejected = total.rejected,
+        "ran {:.0}s, {} hashes at {:.2} MH/s, found {} accepted {} rejected {}",
+        runtime.as_secs_f64(),
+        hashes,
+        hashrate / 1_000_000.0,
+        total.found,
+        total.accepted,
+        total.rejected
+    );
+
+    Ok(())
+}
 
-        if !results.is_empty() {
-            let cloned = ctx.clone();
-            tokio::spawn(async move {
-                submit_work(&results[0], &cloned).await;
+/// Round robin over the tickers, giving each a slice of every `cycle` in proportion
+/// to its weight or expected reward.
+async fn time_slice(ctx: &Context, cycle: Duration) {
+    loop {
+        let mut weights = vec![];
+        for job in ctx.jobs.iter() {
+            weights.push(match ctx.args.schedule {
+                Schedule::Auto => expected_reward(&job.work.load(), job.spec.weight),
+                _ => job.spec.weight,
             });
         }
 
-        nonce = nonce + 1;
+        for (index, slice) in time_slices(&weights, cycle).into_iter().enumerate() {
+            if slice.is_zero() {
+                continue;
+            }
+
+            activate(ctx, index).await;
+            debug!(
+                event = "job_scheduled",
+                ticker = %ctx.jobs[index].spec.tick,
+                slice_ms = slice.as_millis() as u64,
+                "switching ticker"
+            );
+            tokio::time::sleep(slice).await;
+        }
+    }
+}
+
+/// Mines only the best ranked ticker, re-ranking every `interval` as the polled jobs
+/// change.
+async fn switch_to_best(ctx: &Context, interval: Duration) {
+    let mut hysteresis = Hysteresis::new(
+        ctx.args.switch_ratio,
+        Duration::from_secs_f64(ctx.args.min_dwell_secs),
+    );
+
+    loop {
+        let mut scores = vec![];
+        for job in ctx.jobs.iter() {
+            scores.push(ctx.args.score.score(&job.work.load(), job.spec.weight));
+        }
+
+        let from = hysteresis.current();
+        if let Some((to, reason)) = hysteresis.update(&scores) {
+            activate(ctx, to).await;
+            info!(
+                event = "ticker_switched",
+                from = from.map(|from| ctx.jobs[from].spec.tick.as_str()),
+                to = %ctx.jobs[to].spec.tick,
+                score = scores[to],
+                from_score = from.map(|from| scores[from]),
+                ranking = %ctx.args.score,
+                reason = %reason,
+                "switched to {}: {}",
+                ctx.jobs[to].spec.tick,
+                reason
+            );
+        }
+
+        tokio::time::sleep(interval).await;
     }
 }
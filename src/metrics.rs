@@ -0,0 +1,231 @@
+use super::*;
+use std::{
+    fmt::Write as _,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicI64, AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpListener,
+};
+use tracing::warn;
+
+/// Upper bounds of the submit latency histogram buckets, in seconds.
+const LATENCY_BUCKETS: [f64; 10] = [0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+/// Prometheus histogram with fixed buckets.
+#[derive(Debug, Default)]
+pub struct Histogram {
+    /// Observations per bucket, not cumulative; the last one is `+Inf`.
+    counts: [AtomicU64; LATENCY_BUCKETS.len() + 1],
+    sum_micros: AtomicU64,
+}
+
+impl Histogram {
+    pub fn observe(&self, value: Duration) {
+        let secs = value.as_secs_f64();
+        let bucket = LATENCY_BUCKETS
+            .iter()
+            .position(|&le| secs <= le)
+            .unwrap_or(LATENCY_BUCKETS.len());
+
+        self.counts[bucket].fetch_add(1, Ordering::Relaxed);
+        self.sum_micros
+            .fetch_add(value.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    fn render(&self, out: &mut String, name: &str, help: &str) {
+        let _ = writeln!(out, "# HELP {} {}", name, help);
+        let _ = writeln!(out, "# TYPE {} histogram", name);
+
+        let mut cumulative = 0;
+        for (i, count) in self.counts.iter().enumerate() {
+            cumulative += count.load(Ordering::Relaxed);
+            let le = match LATENCY_BUCKETS.get(i) {
+                Some(le) => le.to_string(),
+                None => "+Inf".to_string(),
+            };
+            let _ = writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, le, cumulative);
+        }
+
+        let sum = self.sum_micros.load(Ordering::Relaxed) as f64 / 1_000_000.0;
+        let _ = writeln!(out, "{}_sum {}", name, sum);
+        let _ = writeln!(out, "{}_count {}", name, cumulative);
+    }
+}
+
+/// Counters and gauges exported by `Metrics::serve` in the Prometheus text format.
+#[derive(Debug)]
+pub struct Metrics {
+    pub hashes: AtomicU64,
+    /// Hashes per second over the last bucket, as `f64` bits.
+    hashrate: AtomicU64,
+    pub shares_found: AtomicU64,
+    pub shares_accepted: AtomicU64,
+    pub shares_rejected: AtomicU64,
+    /// Submissions that failed transiently, with no response, a 5xx or a 429, until
+    /// their retries ran out. Those shares stay pending in the journal.
+    pub shares_errored: AtomicU64,
+    pub submit_latency: Histogram,
+    pub job_changes: AtomicU64,
+    pub difficulty: AtomicI64,
+    start: Instant,
+    /// Milliseconds after `start` the job was last fetched successfully.
+    last_job_update: AtomicU64,
+}
+
+impl Default for Metrics {
+    fn default() -> Metrics {
+        Metrics {
+            hashes: AtomicU64::new(0),
+            hashrate: AtomicU64::new(0),
+            shares_found: AtomicU64::new(0),
+            shares_accepted: AtomicU64::new(0),
+            shares_rejected: AtomicU64::new(0),
+            shares_errored: AtomicU64::new(0),
+            submit_latency: Histogram::default(),
+            job_changes: AtomicU64::new(0),
+            difficulty: AtomicI64::new(0),
+            start: Instant::now(),
+            last_job_update: AtomicU64::new(0),
+        }
+    }
+}
+
+impl Metrics {
+    pub fn set_hashrate(&self, hashrate: f64) {
+        self.hashrate.store(hashrate.to_bits(), Ordering::Relaxed);
+    }
+
+    pub fn hashrate(&self) -> f64 {
+        f64::from_bits(self.hashrate.load(Ordering::Relaxed))
+    }
+
+    /// Records a successful job fetch, whether or not the job changed.
+    pub fn job_fetched(&self) {
+        self.last_job_update
+            .store(self.start.elapsed().as_millis() as u64, Ordering::Relaxed);
+    }
+
+    pub fn since_last_job_update(&self) -> Duration {
+        let last = Duration::from_millis(self.last_job_update.load(Ordering::Relaxed));
+        self.start.elapsed().saturating_sub(last)
+    }
+
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        let mut metric = |name: &str, kind: &str, help: &str, value: String| {
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} {}", name, kind);
+            let _ = writeln!(out, "{} {}", name, value);
+        };
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed).to_string();
+
+        metric(
+            "pow20_hashes_total",
+            "counter",
+            "Nonces hashed.",
+            load(&self.hashes),
+        );
+        metric(
+            "pow20_hashrate",
+            "gauge",
+            "Hashes per second over the last bucket.",
+            self.hashrate().to_string(),
+        );
+        metric(
+            "pow20_shares_found_total",
+            "counter",
+            "Solutions found.",
+            load(&self.shares_found),
+        );
+        metric(
+            "pow20_shares_accepted_total",
+            "counter",
+            "Shares accepted by the API.",
+            load(&self.shares_accepted),
+        );
+        metric(
+            "pow20_shares_rejected_total",
+            "counter",
+            "Shares rejected by the API.",
+            load(&self.shares_rejected),
+        );
+        metric(
+            "pow20_shares_errored_total",
+            "counter",
+            "Share submissions that got no response, a 5xx or a 429 until their retries ran out.",
+            load(&self.shares_errored),
+        );
+        metric(
+            "pow20_job_changes_total",
+            "counter",
+            "Times the job was replaced.",
+            load(&self.job_changes),
+        );
+        metric(
+            "pow20_difficulty",
+            "gauge",
+            "Difficulty of the job being mined.",
+            self.difficulty.load(Ordering::Relaxed).to_string(),
+        );
+        metric(
+            "pow20_last_job_update_seconds",
+            "gauge",
+            "Seconds since the job was last fetched successfully.",
+            self.since_last_job_update().as_secs_f64().to_string(),
+        );
+
+        self.submit_latency.render(
+            &mut out,
+            "pow20_submit_latency_seconds",
+            "Time from the first submission of a share to its final result.",
+        );
+
+        out
+    }
+
+    /// Serves `render()` on `GET /metrics` at `addr` until the task is dropped.
+    pub async fn serve(self: Arc<Self>, addr: SocketAddr) -> Result<()> {
+        let listener = TcpListener::bind(addr).await?;
+
+        loop {
+            let mut stream = match listener.accept().await {
+                Ok((stream, _)) => stream,
+                Err(e) => {
+                    // e.g. out of file descriptors, which frees up again
+                    warn!(event = "metrics_accept_failed", error = %e, "failed to accept metrics connection");
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                    continue;
+                }
+            };
+            let metrics = self.clone();
+
+            tokio::spawn(async move {
+                // a scrape is a single small GET, the request line is all that matters
+                let mut request = [0_u8; 1024];
+                let n = stream.read(&mut request).await.unwrap_or(0);
+                let request = String::from_utf8_lossy(&request[..n]);
+
+                let response = if request.starts_with("GET /metrics ") {
+                    let body = metrics.render();
+                    format!(
+                        "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                        body.len(),
+                        body
+                    )
+                } else {
+                    "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+                        .to_string()
+                };
+
+                let _ = stream.write_all(response.as_bytes()).await;
+                let _ = stream.shutdown().await;
+            });
+        }
+    }
+}
@@ -0,0 +1,370 @@
+use super::*;
+use arc_swap::ArcSwap;
+use rayon::prelude::*;
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+    thread::JoinHandle,
+    time::{Duration, Instant},
+};
+
+/// Nonces handed to the hashing workers per round.
+const BUCKET_SIZE: u32 = 1_000_000;
+
+/// Nonces hashed per `sha256d_many` call, a multiple of every backend's lane count.
+const CHUNK_SIZE: usize = 64;
+
+type SolutionCallback = dyn Fn(Solution) + Send + Sync;
+type BucketCallback = dyn Fn(&BucketReport) + Send + Sync;
+
+/// Summary of one bucket of nonces, passed to the `Miner::on_bucket` callback after
+/// that bucket's solutions have been reported.
+#[derive(Clone, Debug)]
+pub struct BucketReport {
+    pub job: Ticker,
+    pub hashes: u64,
+    /// Hashes spent on `job` after its ticker got a new challenge or difficulty. A
+    /// switch to another ticker doesn't make them stale, `job` is still current then.
+    pub stale_hashes: u64,
+    pub solutions: usize,
+    pub elapsed: Duration,
+}
+
+impl BucketReport {
+    /// Hashrate over this bucket in hashes per second.
+    pub fn hashrate(&self) -> f64 {
+        self.hashes as f64 / self.elapsed.as_secs_f64()
+    }
+}
+
+/// A published job together with a counter bumped every time it changes, so the
+/// hashing workers can tell that the job they loaded was replaced.
+struct Snapshot {
+    job: Ticker,
+    generation: u64,
+}
+
+/// The job being mined, swapped atomically so that neither the workers reading it nor
+/// `Miner::update_job` publishing a new one ever wait on each other.
+struct Current {
+    job: ArcSwap<Snapshot>,
+    running: AtomicBool,
+    hashes: AtomicU64,
+    thread_hashes: Vec<AtomicU64>,
+}
+
+/// Hashes nonces for one job at a time on a dedicated thread pool and reports every
+/// nonce that meets the job's difficulty through a callback. `start`, `stop` and
+/// `update_job` may be called from any thread.
+pub struct Miner {
+    current: Arc<Current>,
+    nonces: Arc<NonceSpace>,
+    threads: usize,
+    backend: Backend,
+    cpus: Vec<usize>,
+    nice: i32,
+    on_solution: Arc<SolutionCallback>,
+    on_bucket: Option<Arc<BucketCallback>>,
+    worker: std::sync::Mutex<Option<JoinHandle<()>>>,
+}
+
+impl Miner {
+    /// A `threads` of 0 starts one hashing thread per CPU.
+    pub fn new(
+        job: Ticker,
+        threads: usize,
+        on_solution: impl Fn(Solution) + Send + Sync + 'static,
+    ) -> Result<Miner> {
+        challenge_bytes(&job)?;
+        let threads = if threads == 0 {
+            num_cpus::get()
+        } else {
+            threads
+        };
+
+        Ok(Miner {
+            current: Arc::new(Current {
+                job: ArcSwap::from_pointee(Snapshot { job, generation: 0 }),
+                running: AtomicBool::new(false),
+                hashes: AtomicU64::new(0),
+                thread_hashes: (0..threads).map(|_| AtomicU64::new(0)).collect(),
+            }),
+            nonces: Arc::new(NonceSpace::default()),
+            threads,
+            backend: Backend::detect(),
+            cpus: vec![],
+            nice: 0,
+            on_solution: Arc::new(on_solution),
+            on_bucket: None,
+            worker: std::sync::Mutex::new(None),
+        })
+    }
+
+    /// Hashing backend, defaults to `Backend::detect()`.
+    pub fn backend(mut self, backend: Backend) -> Miner {
+        self.backend = backend;
+        self
+    }
+
+    /// CPUs the hashing threads are pinned to, round robin when there are more threads
+    /// than CPUs. Empty, the default, leaves scheduling to the OS.
+    pub fn cpus(mut self, cpus: Vec<usize>) -> Miner {
+        self.cpus = cpus;
+        self
+    }
+
+    /// Nice value the hashing threads run at, 0 (the default) keeps the priority of
+    /// the calling process.
+    pub fn nice(mut self, nice: i32) -> Miner {
+        self.nice = nice;
+        self
+    }
+
+    /// Where the nonces come from, defaults to worker id 0. Machines mining the same
+    /// ticker need different worker ids to not duplicate each other's work. Takes
+    /// effect on the next `start`.
+    pub fn nonce_space(mut self, nonces: NonceSpace) -> Miner {
+        self.nonces = Arc::new(nonces);
+        self
+    }
+
+    /// Called after every bucket of nonces, e.g. to report the hashrate.
+    pub fn on_bucket(mut self, on_bucket: impl Fn(&BucketReport) + Send + Sync + 'static) -> Miner {
+        self.on_bucket = Some(Arc::new(on_bucket));
+        self
+    }
+
+    pub fn start(&self) -> Result<()> {
+        let mut worker = self.worker.lock().unwrap();
+        if worker.is_some() {
+            return Ok(());
+        }
+
+        if !self.backend.is_supported() {
+            anyhow::bail!("backend {} is not supported by this CPU", self.backend);
+        }
+
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(self.threads)
+            .thread_name(|i| format!("pow20-hash-{}", i))
+            .build()?;
+
+        let cpus = &self.cpus;
+        let nice = self.nice;
+        pool.broadcast(|ctx| -> Result<()> {
+            if !cpus.is_empty() {
+                pin_current_thread(cpus[ctx.index() % cpus.len()])?;
+            }
+            if nice != 0 {
+                lower_current_thread_priority(nice)?;
+            }
+            Ok(())
+        })
+        .into_iter()
+        .collect::<Result<()>>()?;
+
+        self.current.running.store(true, Ordering::SeqCst);
+
+        let current = self.current.clone();
+        let nonces = self.nonces.clone();
+        let backend = self.backend;
+        let on_solution = self.on_solution.clone();
+        let on_bucket = self.on_bucket.clone();
+        *worker = Some(
+            std::thread::Builder::new()
+                .name("pow20-miner".to_string())
+                .spawn(move || {
+                    pool.install(|| {
+                        run(
+                            &current,
+                            &nonces,
+                            backend,
+                            &*on_solution,
+                            on_bucket.as_deref(),
+                        )
+                    })
+                })?,
+        );
+
+        Ok(())
+    }
+
+    /// Stops hashing, abandoning the current bucket, and waits for the workers to exit.
+    pub fn stop(&self) {
+        self.current.running.store(false, Ordering::SeqCst);
+        if let Some(worker) = self.worker.lock().unwrap().take() {
+            let _ = worker.join();
+        }
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.current.running.load(Ordering::SeqCst)
+    }
+
+    /// Switches the workers to `job`; the bucket in progress is abandoned.
+    pub fn update_job(&self, job: Ticker) -> Result<()> {
+        challenge_bytes(&job)?;
+
+        self.current.job.rcu(|current| Snapshot {
+            job: job.clone(),
+            generation: current.generation + 1,
+        });
+
+        Ok(())
+    }
+
+    pub fn job(&self) -> Ticker {
+        self.current.job.load().job.clone()
+    }
+
+    /// Nonces hashed since the miner was created.
+    pub fn hashes(&self) -> u64 {
+        self.current.hashes.load(Ordering::Relaxed)
+    }
+
+    /// Nonces hashed by each worker thread since the miner was created.
+    pub fn thread_hashes(&self) -> Vec<u64> {
+        self.current
+            .thread_hashes
+            .iter()
+            .map(|hashes| hashes.load(Ordering::Relaxed))
+            .collect()
+    }
+
+    /// Nonces handed out to the workers per challenge so far.
+    pub fn coverage(&self) -> Vec<Coverage> {
+        self.nonces.coverage()
+    }
+
+    /// Number of times the job has been replaced.
+    pub fn generation(&self) -> u64 {
+        self.current.job.load().generation
+    }
+}
+
+impl Drop for Miner {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+/// The challenge as it appears in the preimage: the job's hex challenge, byte reversed.
+pub fn challenge_bytes(job: &Ticker) -> Result<Vec<u8>> {
+    let mut challenge_bytes = hex::decode(&job.challenge)?;
+    if challenge_bytes.len() < 4 {
+        anyhow::bail!("challenge {:?} is too short", job.challenge);
+    }
+
+    challenge_bytes.reverse();
+    Ok(challenge_bytes)
+}
+
+fn run(
+    current: &Current,
+    nonce_space: &NonceSpace,
+    backend: Backend,
+    on_solution: &SolutionCallback,
+    on_bucket: Option<&BucketCallback>,
+) {
+    let offsets = (0..BUCKET_SIZE).collect::<Vec<u32>>();
+
+    while current.running.load(Ordering::SeqCst) {
+        let start_time = Instant::now();
+
+        let snapshot = current.job.load_full();
+        let work = &snapshot.job;
+
+        // the bucket is cut short when any other job is published, but its hashes
+        // only go to waste when that job replaces this one's challenge or difficulty
+        let moved_on = || {
+            current.job.load().generation != snapshot.generation
+                || !current.running.load(Ordering::Relaxed)
+        };
+        let is_stale = || {
+            let now = current.job.load();
+            !current.running.load(Ordering::Relaxed)
+                || (now.job.id == work.id
+                    && (now.job.challenge != work.challenge
+                        || now.job.difficulty != work.difficulty))
+        };
+        let hashed = AtomicU64::new(0);
+        let stale_hashes = AtomicU64::new(0);
+
+        // validated by `Miner::new` and `Miner::update_job`
+        let challenge_bytes = challenge_bytes(work).unwrap();
+        let job = Hash::prepare(&challenge_bytes);
+        let target = Target::new(work.difficulty);
+
+        let Some(range) = nonce_space.allocate(&challenge_bytes, BUCKET_SIZE as u64) else {
+            // every nonce of this challenge has been tried, wait for the next job
+            std::thread::sleep(Duration::from_millis(100));
+            continue;
+        };
+        let bucket = &offsets[..(range.end - range.start) as usize];
+
+        let results = bucket
+            .par_chunks(CHUNK_SIZE)
+            .flat_map_iter(|offsets| {
+                if moved_on() {
+                    return vec![];
+                }
+
+                let mut nonces = [[0_u8; 8]; CHUNK_SIZE];
+                for (data, offset) in nonces.iter_mut().zip(offsets) {
+                    *data = nonce_space.nonce(range.start + *offset as u64);
+                }
+
+                let nonces = &nonces[..offsets.len()];
+                let mut hashes = [[0_u8; 32]; CHUNK_SIZE];
+                let hashes = &mut hashes[..offsets.len()];
+                job.sha256d_many(backend, nonces, hashes);
+                hashed.fetch_add(nonces.len() as u64, Ordering::Relaxed);
+                current
+                    .hashes
+                    .fetch_add(nonces.len() as u64, Ordering::Relaxed);
+                if let Some(hashes) =
+                    rayon::current_thread_index().and_then(|i| current.thread_hashes.get(i))
+                {
+                    hashes.fetch_add(nonces.len() as u64, Ordering::Relaxed);
+                }
+
+                // the job changed while this chunk was hashed, its solutions are worthless
+                if moved_on() && is_stale() {
+                    stale_hashes.fetch_add(nonces.len() as u64, Ordering::Relaxed);
+                    return vec![];
+                }
+
+                nonces
+                    .iter()
+                    .zip(hashes.iter())
+                    .filter(|(_, solution)| meets_difficulty(solution, &target))
+                    .map(|(data, solution)| Solution {
+                        nonce: hex::encode(data),
+                        hash: hex::encode(solution),
+                        location: work.current_location.clone(),
+                        token_id: work.id.clone(),
+                        challenge: challenge_bytes.clone(),
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+
+        let report = BucketReport {
+            job: work.clone(),
+            hashes: hashed.into_inner(),
+            stale_hashes: stale_hashes.into_inner(),
+            solutions: results.len(),
+            elapsed: start_time.elapsed(),
+        };
+
+        for solution in results {
+            on_solution(solution);
+        }
+
+        if let Some(on_bucket) = on_bucket {
+            on_bucket(&report);
+        }
+    }
+}
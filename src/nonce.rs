@@ -0,0 +1,213 @@
+use super::*;
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{BufReader, BufWriter, Write},
+    ops::Range,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+use tracing::warn;
+
+/// Nonces per challenge and worker: the six bytes after the worker id.
+pub const NONCES_PER_WORKER: u64 = 1 << 48;
+
+/// Counters reserved in the nonce file at a time, so it is written once per this many
+/// nonces rather than once per bucket. A restart skips what was left of a reservation.
+const RESERVE_AHEAD: u64 = 1 << 32;
+
+/// Challenges kept in the nonce file, the least recently mined are dropped first.
+const MAX_STORED: usize = 1024;
+
+/// Splits the 8-byte nonce space between machines and threads without randomness. The
+/// first two bytes are the worker id, so machines with different ids never try the
+/// same nonce, and the other six count up from 0 for every challenge, handed out in
+/// disjoint ranges to the hashing threads.
+#[derive(Debug, Default)]
+pub struct NonceSpace {
+    worker_id: u16,
+    /// Counters handed out so far per challenge, so a job that is mined again resumes
+    /// where it left off instead of trying the same nonces twice.
+    assigned: Mutex<HashMap<Vec<u8>, u64>>,
+    /// Where the counters are kept across restarts, see `NonceSpace::with_file`.
+    store: Option<NonceStore>,
+}
+
+/// How much of a challenge's nonce space this worker has handed out.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Coverage {
+    /// The challenge as it is hashed, like `Solution::challenge`.
+    #[serde(with = "hex")]
+    pub challenge: Vec<u8>,
+    pub worker_id: u16,
+    /// Counters `0..assigned` have been handed out.
+    pub assigned: u64,
+}
+
+#[derive(Debug)]
+struct NonceStore {
+    path: PathBuf,
+    /// Counters reserved per challenge and worker id, least recently extended first.
+    reserved: Mutex<Vec<Coverage>>,
+}
+
+impl NonceStore {
+    fn save(&self, reserved: &[Coverage]) -> Result<()> {
+        // written aside and renamed over, so a crash never leaves half a file
+        let tmp = PathBuf::from(format!("{}.tmp", self.path.display()));
+        let mut file = BufWriter::new(File::create(&tmp)?);
+        serde_json::to_writer_pretty(&mut file, reserved)?;
+        file.flush()?;
+        file.get_ref().sync_all()?;
+        std::fs::rename(&tmp, &self.path)?;
+
+        Ok(())
+    }
+}
+
+impl NonceSpace {
+    pub fn new(worker_id: u16) -> NonceSpace {
+        NonceSpace {
+            worker_id,
+            assigned: Mutex::new(HashMap::new()),
+            store: None,
+        }
+    }
+
+    /// A worker id derived from `seed`, for fleets that name machines rather than
+    /// number them. Different seeds can still map to the same id: there are only 65536,
+    /// so among 300 seeds two share one about half the time.
+    pub fn from_seed(seed: &str) -> NonceSpace {
+        let hash = Hash::sha256d(seed.as_bytes());
+        NonceSpace::new(u16::from_be_bytes([hash[0], hash[1]]))
+    }
+
+    /// Keeps the counters in the JSON file at `path` as well, so that after a restart
+    /// every challenge resumes past what was handed out before. The file may hold the
+    /// counters of other worker ids too; a missing file is an empty one.
+    pub fn with_file(mut self, path: &Path) -> Result<NonceSpace> {
+        let reserved: Vec<Coverage> = match File::open(path) {
+            Ok(file) => serde_json::from_reader(BufReader::new(file))
+                .map_err(|e| anyhow::anyhow!("{}: {}", path.display(), e))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => vec![],
+            Err(e) => return Err(e.into()),
+        };
+
+        let assigned = self.assigned.get_mut().unwrap();
+        for coverage in reserved.iter().filter(|c| c.worker_id == self.worker_id) {
+            let next = assigned.entry(coverage.challenge.clone()).or_insert(0);
+            *next = (*next).max(coverage.assigned);
+        }
+
+        self.store = Some(NonceStore {
+            path: path.to_path_buf(),
+            reserved: Mutex::new(reserved),
+        });
+        Ok(self)
+    }
+
+    pub fn worker_id(&self) -> u16 {
+        self.worker_id
+    }
+
+    /// Reserves the next `count` counters of `challenge`, fewer when the space runs
+    /// out, and `None` once it is exhausted.
+    pub fn allocate(&self, challenge: &[u8], count: u64) -> Option<Range<u64>> {
+        let mut assigned = self.assigned.lock().unwrap();
+        let next = assigned.entry(challenge.to_vec()).or_insert(0);
+        if *next >= NONCES_PER_WORKER {
+            return None;
+        }
+
+        let start = *next;
+        *next = start.saturating_add(count).min(NONCES_PER_WORKER);
+        if let Some(store) = &self.store {
+            self.reserve(store, challenge, *next);
+        }
+        Some(start..*next)
+    }
+
+    /// Makes sure the nonce file covers counters up to `end` of `challenge`. A file
+    /// that can't be written is logged, the counters then only live in memory.
+    fn reserve(&self, store: &NonceStore, challenge: &[u8], end: u64) {
+        let mut reserved = store.reserved.lock().unwrap();
+        let at = reserved
+            .iter()
+            .position(|c| c.challenge == challenge && c.worker_id == self.worker_id);
+        if at.is_some_and(|at| reserved[at].assigned >= end) {
+            return;
+        }
+
+        if let Some(at) = at {
+            reserved.remove(at);
+        }
+        reserved.push(Coverage {
+            challenge: challenge.to_vec(),
+            worker_id: self.worker_id,
+            assigned: end.saturating_add(RESERVE_AHEAD).min(NONCES_PER_WORKER),
+        });
+        let excess = reserved.len().saturating_sub(MAX_STORED);
+        reserved.drain(..excess);
+
+        if let Err(e) = store.save(&reserved) {
+            warn!(
+                event = "nonce_file_write_failed",
+                path = %store.path.display(),
+                error = %e,
+                "failed to write nonce file, a restart may try the same nonces again"
+            );
+        }
+    }
+
+    /// The 8-byte nonce for `counter`: the worker id then the counter, big endian.
+    pub fn nonce(&self, counter: u64) -> [u8; 8] {
+        let mut nonce = counter.to_be_bytes();
+        nonce[..2].copy_from_slice(&self.worker_id.to_be_bytes());
+        nonce
+    }
+
+    /// What has been handed out for every challenge seen so far.
+    pub fn coverage(&self) -> Vec<Coverage> {
+        self.assigned
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(challenge, assigned)| Coverage {
+                challenge: challenge.clone(),
+                worker_id: self.worker_id,
+                assigned: *assigned,
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn nonce_file_resumes_past_handed_out_counters() {
+        let path = std::env::temp_dir().join(format!("pow20-nonces-{}.json", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let nonces = NonceSpace::new(7).with_file(&path).unwrap();
+        assert_eq!(nonces.allocate(b"one", 1000), Some(0..1000));
+        assert_eq!(nonces.allocate(b"two", 10), Some(0..10));
+        drop(nonces);
+
+        let nonces = NonceSpace::new(7).with_file(&path).unwrap();
+        let resumed = nonces.allocate(b"one", 1000).unwrap();
+        assert!(resumed.start >= 1000, "{:?}", resumed);
+        assert!(nonces.allocate(b"two", 10).unwrap().start >= 10);
+        drop(nonces);
+
+        // other worker ids have their own counters in the same file
+        let other = NonceSpace::new(8).with_file(&path).unwrap();
+        assert_eq!(other.allocate(b"one", 1000), Some(0..1000));
+        drop(other);
+        let nonces = NonceSpace::new(7).with_file(&path).unwrap();
+        assert!(nonces.allocate(b"one", 1).unwrap().start >= resumed.end);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
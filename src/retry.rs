@@ -0,0 +1,23 @@
+use super::*;
+use std::time::Duration;
+
+/// Capped exponential backoff with full jitter for share submission.
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    pub max_retries: u32,
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+}
+
+impl RetryPolicy {
+    /// Delay before retry number `attempt` (starting at 0): a random duration up to
+    /// `base_delay * 2^attempt`, capped at `max_delay`.
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let ceiling = self
+            .base_delay
+            .saturating_mul(2_u32.saturating_pow(attempt))
+            .min(self.max_delay);
+
+        ceiling.mul_f64(rand::thread_rng().gen::<f64>())
+    }
+}
@@ -0,0 +1,246 @@
+use super::*;
+use std::{
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+/// A ticker to mine and its weight, written `TICK` or `TICK:WEIGHT` on the command line.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TickSpec {
+    pub tick: String,
+    /// Share of the CPU time in weighted scheduling, or the relative value of one of
+    /// its shares when scheduling by expected reward. Defaults to 1.
+    pub weight: f64,
+}
+
+impl FromStr for TickSpec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<TickSpec, String> {
+        let (tick, weight) = match s.split_once(':') {
+            Some((tick, weight)) => {
+                let weight = weight
+                    .trim()
+                    .parse::<f64>()
+                    .map_err(|e| format!("invalid weight in {:?}: {}", s, e))?;
+                (tick.trim(), weight)
+            }
+            None => (s.trim(), 1.0),
+        };
+
+        if tick.is_empty() {
+            return Err(format!("missing ticker in {:?}", s));
+        }
+        if !weight.is_finite() || weight < 0.0 {
+            return Err(format!("weight in {:?} must be a non-negative number", s));
+        }
+
+        Ok(TickSpec {
+            tick: tick.to_string(),
+            weight,
+        })
+    }
+}
+
+/// Expected reward per hash of mining `job`: the chance that a hash meets its
+/// difficulty, 16^-difficulty, times `weight` as the value of a share.
+pub fn expected_reward(job: &Ticker, weight: f64) -> f64 {
+    weight * 16_f64.powi(-job.difficulty.clamp(0, 64))
+}
+
+/// How tickers are ranked against each other; higher is better.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Score {
+    /// `expected_reward` with the ticker's weight.
+    Reward,
+    /// The chance that a hash meets the difficulty, ignoring weights.
+    Difficulty,
+}
+
+impl Score {
+    pub const ALL: [Score; 2] = [Score::Reward, Score::Difficulty];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Score::Reward => "reward",
+            Score::Difficulty => "difficulty",
+        }
+    }
+
+    pub fn score(&self, job: &Ticker, weight: f64) -> f64 {
+        match self {
+            Score::Reward => expected_reward(job, weight),
+            Score::Difficulty => expected_reward(job, 1.0),
+        }
+    }
+}
+
+impl std::fmt::Display for Score {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for Score {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Score, String> {
+        Score::ALL
+            .into_iter()
+            .find(|score| score.name() == s)
+            .ok_or_else(|| {
+                let names = Score::ALL.map(|score| score.name()).join(", ");
+                format!("unknown score {:?}, expected one of: {}", s, names)
+            })
+    }
+}
+
+/// Splits `cycle` between jobs in proportion to `weights`. Jobs with a weight of 0
+/// get no time; if every weight is 0 the time is split evenly.
+pub fn time_slices(weights: &[f64], cycle: Duration) -> Vec<Duration> {
+    let total = weights.iter().sum::<f64>();
+    if total <= 0.0 {
+        return vec![cycle / weights.len().max(1) as u32; weights.len()];
+    }
+
+    weights
+        .iter()
+        .map(|weight| cycle.mul_f64(weight / total))
+        .collect()
+}
+
+/// Keeps mining the current job until another one scores at least `min_ratio` times
+/// as much and the current one has been mined for `min_dwell`, so that jobs with
+/// similar scores don't make the miner flap between them.
+#[derive(Clone, Debug)]
+pub struct Hysteresis {
+    pub min_ratio: f64,
+    pub min_dwell: Duration,
+    current: Option<(usize, Instant)>,
+}
+
+/// Why `Hysteresis::update` picked a new job.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SwitchReason {
+    /// Nothing was being mined yet.
+    Initial,
+    /// The new job scores `ratio` times the current one.
+    BetterScore { ratio: f64 },
+}
+
+impl std::fmt::Display for SwitchReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            SwitchReason::Initial => write!(f, "initial pick"),
+            SwitchReason::BetterScore { ratio } => {
+                write!(f, "scores {:.2}x the current ticker", ratio)
+            }
+        }
+    }
+}
+
+impl Hysteresis {
+    pub fn new(min_ratio: f64, min_dwell: Duration) -> Hysteresis {
+        Hysteresis {
+            min_ratio,
+            min_dwell,
+            current: None,
+        }
+    }
+
+    pub fn current(&self) -> Option<usize> {
+        self.current.map(|(index, _)| index)
+    }
+
+    /// Index of the job to switch to given the latest `scores`, or `None` to stay. Ties
+    /// go to the job listed first.
+    pub fn update(&mut self, scores: &[f64]) -> Option<(usize, SwitchReason)> {
+        let (best, best_score) = scores.iter().copied().enumerate().reduce(|best, score| {
+            if score.1 > best.1 {
+                score
+            } else {
+                best
+            }
+        })?;
+
+        let reason = match self.current {
+            None => SwitchReason::Initial,
+            Some((current, _)) if current == best => return None,
+            Some((_, since)) if since.elapsed() < self.min_dwell => return None,
+            Some((current, _)) => {
+                let ratio = best_score / scores[current];
+                if ratio < self.min_ratio {
+                    return None;
+                }
+                SwitchReason::BetterScore { ratio }
+            }
+        };
+
+        self.current = Some((best, Instant::now()));
+        Some((best, reason))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hysteresis_picks_the_best_job_first() {
+        let mut hysteresis = Hysteresis::new(2.0, Duration::from_secs(3600));
+        assert_eq!(hysteresis.update(&[]), None);
+        assert_eq!(
+            hysteresis.update(&[1.0, 3.0, 2.0]),
+            Some((1, SwitchReason::Initial))
+        );
+        assert_eq!(hysteresis.current(), Some(1));
+        assert_eq!(hysteresis.update(&[1.0, 3.0, 2.0]), None);
+    }
+
+    #[test]
+    fn hysteresis_stays_below_min_ratio() {
+        let mut hysteresis = Hysteresis::new(2.0, Duration::ZERO);
+        hysteresis.update(&[1.0, 0.5]);
+        assert_eq!(hysteresis.update(&[1.0, 1.9]), None);
+        assert_eq!(hysteresis.current(), Some(0));
+        assert_eq!(
+            hysteresis.update(&[1.0, 2.0]),
+            Some((1, SwitchReason::BetterScore { ratio: 2.0 }))
+        );
+    }
+
+    #[test]
+    fn hysteresis_stays_inside_min_dwell() {
+        let dwell = Duration::from_millis(50);
+        let mut hysteresis = Hysteresis::new(2.0, dwell);
+        hysteresis.update(&[1.0, 0.5]);
+        assert_eq!(hysteresis.update(&[1.0, 10.0]), None);
+        assert_eq!(hysteresis.current(), Some(0));
+
+        std::thread::sleep(dwell);
+        assert_eq!(
+            hysteresis.update(&[1.0, 10.0]),
+            Some((1, SwitchReason::BetterScore { ratio: 10.0 }))
+        );
+        assert_eq!(hysteresis.current(), Some(1));
+        // the dwell starts over with the new job
+        assert_eq!(hysteresis.update(&[100.0, 10.0]), None);
+    }
+
+    #[test]
+    fn hysteresis_ties_go_to_the_job_listed_first() {
+        let mut hysteresis = Hysteresis::new(1.0, Duration::ZERO);
+        assert_eq!(
+            hysteresis.update(&[0.5, 2.0, 2.0]),
+            Some((1, SwitchReason::Initial))
+        );
+        assert_eq!(hysteresis.update(&[0.5, 2.0, 2.0]), None);
+
+        let mut hysteresis = Hysteresis::new(1.0, Duration::ZERO);
+        hysteresis.update(&[0.5, 0.5, 2.0]);
+        assert_eq!(
+            hysteresis.update(&[4.0, 4.0, 2.0]),
+            Some((0, SwitchReason::BetterScore { ratio: 2.0 }))
+        );
+    }
+}
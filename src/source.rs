@@ -0,0 +1,454 @@
+use super::*;
+use std::{
+    future::Future,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+use tokio::sync::Notify;
+use tracing::{debug, info, warn};
+
+pub type JobFuture<'a> = Pin<Box<dyn Future<Output = Result<Ticker>> + Send + 'a>>;
+
+/// Where the jobs of one ticker come from.
+pub trait JobSource: Send {
+    /// Waits for the next job. It may be the same as the last one, callers compare.
+    fn next_job(&mut self) -> JobFuture<'_>;
+}
+
+/// How long to wait between polls: `min` after a share or a new job, doubling up to
+/// `max` with every poll that finds the job unchanged.
+#[derive(Debug)]
+pub struct AdaptiveInterval {
+    pub min: Duration,
+    pub max: Duration,
+    current_ms: AtomicU64,
+    wake: Notify,
+}
+
+impl AdaptiveInterval {
+    pub fn new(min: Duration, max: Duration) -> AdaptiveInterval {
+        AdaptiveInterval {
+            min,
+            max: max.max(min),
+            current_ms: AtomicU64::new(min.as_millis() as u64),
+            wake: Notify::new(),
+        }
+    }
+
+    pub fn current(&self) -> Duration {
+        Duration::from_millis(self.current_ms.load(Ordering::Relaxed))
+    }
+
+    /// A share was submitted, the job is likely to change soon.
+    pub fn share_found(&self) {
+        self.reset();
+        self.wake.notify_one();
+    }
+
+    /// The job is known to be outdated: the next poll happens right away.
+    pub fn poll_now(&self) {
+        self.current_ms.store(0, Ordering::Relaxed);
+        self.wake.notify_one();
+    }
+
+    pub fn changed(&self) {
+        self.reset();
+    }
+
+    pub fn unchanged(&self) {
+        let next = self.current().saturating_mul(2).clamp(self.min, self.max);
+        self.current_ms
+            .store(next.as_millis() as u64, Ordering::Relaxed);
+    }
+
+    fn reset(&self) {
+        self.current_ms
+            .store(self.min.as_millis() as u64, Ordering::Relaxed);
+    }
+
+    /// Sleeps for the current interval, cut short when a share is found or a poll is
+    /// requested meanwhile.
+    pub async fn wait(&self) {
+        let start = tokio::time::Instant::now();
+        loop {
+            tokio::select! {
+                _ = tokio::time::sleep_until(start + self.current()) => return,
+                _ = self.wake.notified() => {}
+            }
+        }
+    }
+}
+
+/// Fetches the ticker with conditional requests, waiting `interval` between polls.
+pub struct Polling {
+    pub api_client: ApiClient,
+    pub tick: String,
+    pub interval: Arc<AdaptiveInterval>,
+    cache: CachedTicker,
+}
+
+impl Polling {
+    pub fn new(api_client: ApiClient, tick: String, interval: Arc<AdaptiveInterval>) -> Polling {
+        Polling {
+            api_client,
+            tick,
+            interval,
+            cache: CachedTicker::default(),
+        }
+    }
+}
+
+impl JobSource for Polling {
+    fn next_job(&mut self) -> JobFuture<'_> {
+        Box::pin(async move {
+            self.interval.wait().await;
+
+            let last = self
+                .cache
+                .ticker
+                .as_ref()
+                .map(|ticker| (ticker.challenge.clone(), ticker.difficulty));
+            let ticker = self
+                .api_client
+                .fetch_ticker_cached(&self.tick, &mut self.cache)
+                .await?;
+
+            if last == Some((ticker.challenge.clone(), ticker.difficulty)) {
+                self.interval.unchanged();
+            } else {
+                self.interval.changed();
+            }
+            Ok(ticker)
+        })
+    }
+}
+
+/// Jobs pushed by the server as server-sent events, one ticker JSON per event.
+pub struct EventStream {
+    pub api_client: ApiClient,
+    pub url: String,
+    /// The connection counts as dropped when nothing, not even a comment, arrives for
+    /// this long.
+    pub idle_timeout: Duration,
+    response: Option<reqwest::Response>,
+    buffer: Vec<u8>,
+}
+
+impl EventStream {
+    pub fn new(api_client: ApiClient, url: String) -> EventStream {
+        EventStream {
+            api_client,
+            url,
+            idle_timeout: Duration::from_secs(60),
+            response: None,
+            buffer: vec![],
+        }
+    }
+
+    async fn connect(&mut self) -> Result<reqwest::Response> {
+        let res = self
+            .api_client
+            .get_url(&self.url)
+            .header("Accept", "text/event-stream")
+            // the stream is meant to stay open, only the idle timeout applies
+            .timeout(Duration::from_secs(24 * 60 * 60))
+            .send()
+            .await?;
+
+        let content_type = res
+            .headers()
+            .get(reqwest::header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or_default();
+        if !res.status().is_success() || !content_type.starts_with("text/event-stream") {
+            anyhow::bail!(
+                "{} offers no event stream: {} {}",
+                self.url,
+                res.status(),
+                content_type
+            );
+        }
+
+        self.buffer.clear();
+        Ok(res)
+    }
+
+    /// Takes the data of the next complete event out of the buffer, if there is one.
+    /// Events without data, like keep-alive comments, are skipped. Only complete events
+    /// are decoded, a character split between two chunks stays in the buffer whole.
+    fn take_event(&mut self) -> Option<String> {
+        loop {
+            if self.buffer.contains(&b'\r') {
+                // `\r\n` becomes `\n`, a `\r` ending the buffer waits for its `\n`
+                let mut bytes = self.buffer.iter().copied().peekable();
+                let mut normalized = Vec::with_capacity(self.buffer.len());
+                while let Some(byte) = bytes.next() {
+                    if byte != b'\r' || bytes.peek() != Some(&b'\n') {
+                        normalized.push(byte);
+                    }
+                }
+                self.buffer = normalized;
+            }
+
+            let end = self.buffer.windows(2).position(|pair| pair == b"\n\n")?;
+            let event = String::from_utf8_lossy(&self.buffer[..end]).into_owned();
+            self.buffer.drain(..end + 2);
+
+            let data = event
+                .lines()
+                .filter_map(|line| line.strip_prefix("data:"))
+                .map(|data| data.strip_prefix(' ').unwrap_or(data))
+                .collect::<Vec<_>>()
+                .join("\n");
+
+            if !data.is_empty() {
+                return Some(data);
+            }
+        }
+    }
+}
+
+impl JobSource for EventStream {
+    fn next_job(&mut self) -> JobFuture<'_> {
+        Box::pin(async move {
+            loop {
+                while let Some(data) = self.take_event() {
+                    match serde_json::from_str::<Ticker>(&data) {
+                        Ok(ticker) => return Ok(ticker),
+                        Err(e) => {
+                            debug!(event = "push_invalid", url = %self.url, error = %e, "skipping pushed event")
+                        }
+                    }
+                }
+
+                let mut response = match self.response.take() {
+                    Some(response) => response,
+                    None => self.connect().await?,
+                };
+                match tokio::time::timeout(self.idle_timeout, response.chunk()).await {
+                    Ok(Ok(Some(chunk))) => self.buffer.extend_from_slice(&chunk),
+                    Ok(Ok(None)) => anyhow::bail!("event stream closed"),
+                    Ok(Err(e)) => return Err(e.into()),
+                    Err(_) => anyhow::bail!("event stream idle for {:?}", self.idle_timeout),
+                }
+                self.response = Some(response);
+            }
+        })
+    }
+}
+
+/// Takes jobs from `push` and switches to `poll` for `retry_after` whenever `push`
+/// fails, then tries `push` again.
+pub struct Fallback {
+    pub tick: String,
+    pub push: Box<dyn JobSource>,
+    pub poll: Box<dyn JobSource>,
+    pub retry_after: Duration,
+    polling_until: Option<Instant>,
+}
+
+impl Fallback {
+    pub fn new(
+        tick: String,
+        push: Box<dyn JobSource>,
+        poll: Box<dyn JobSource>,
+        retry_after: Duration,
+    ) -> Fallback {
+        Fallback {
+            tick,
+            push,
+            poll,
+            retry_after,
+            polling_until: None,
+        }
+    }
+}
+
+impl JobSource for Fallback {
+    fn next_job(&mut self) -> JobFuture<'_> {
+        Box::pin(async move {
+            match self.polling_until {
+                Some(until) if Instant::now() < until => return self.poll.next_job().await,
+                Some(_) => debug!(event = "push_retry", ticker = %self.tick, "retrying push"),
+                None => {}
+            }
+
+            match self.push.next_job().await {
+                Ok(job) => {
+                    if self.polling_until.take().is_some() {
+                        info!(event = "push_resumed", ticker = %self.tick, "receiving pushed jobs again");
+                    }
+                    Ok(job)
+                }
+                Err(e) => {
+                    if self.polling_until.is_none() {
+                        warn!(
+                            event = "push_failed",
+                            ticker = %self.tick,
+                            error = %e,
+                            retry_secs = self.retry_after.as_secs_f64(),
+                            "push dropped, falling back to polling"
+                        );
+                    }
+                    self.polling_until = Some(Instant::now() + self.retry_after);
+                    self.poll.next_job().await
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+        sync::mpsc,
+    };
+
+    fn ticker(name: &str) -> Ticker {
+        Ticker {
+            challenge: "ab".repeat(32),
+            current_location: format!("{}_0", name),
+            difficulty: 6,
+            ticker: name.to_string(),
+            id: format!("id-{}", name),
+        }
+    }
+
+    fn event(ticker: &Ticker) -> Vec<u8> {
+        let json = json!({
+            "challenge": ticker.challenge,
+            "currentLocation": ticker.current_location,
+            "difficulty": ticker.difficulty,
+            "ticker": ticker.ticker,
+            "id": ticker.id,
+        });
+        format!("data: {}\r\n\r\n", json).into_bytes()
+    }
+
+    /// An event stream server: every connection writes the next script's chunks with a
+    /// pause in between, then closes. Reports the number of every accepted connection.
+    async fn serve(scripts: Vec<Vec<Vec<u8>>>) -> (String, mpsc::UnboundedReceiver<usize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/events", listener.local_addr().unwrap());
+        let (tx, rx) = mpsc::unbounded_channel();
+
+        tokio::spawn(async move {
+            for (i, chunks) in scripts.into_iter().enumerate() {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let _ = tx.send(i);
+
+                let mut request = vec![];
+                let mut buf = [0_u8; 1024];
+                while !request.ends_with(b"\r\n\r\n") {
+                    let n = stream.read(&mut buf).await.unwrap();
+                    request.extend_from_slice(&buf[..n]);
+                }
+
+                stream
+                    .write_all(b"HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\nConnection: close\r\n\r\n")
+                    .await
+                    .unwrap();
+                for chunk in chunks {
+                    stream.write_all(&chunk).await.unwrap();
+                    stream.flush().await.unwrap();
+                    tokio::time::sleep(Duration::from_millis(20)).await;
+                }
+                stream.shutdown().await.unwrap();
+            }
+        });
+
+        (url, rx)
+    }
+
+    fn event_stream(url: String) -> EventStream {
+        EventStream::new(ApiClient::new(String::new(), String::new()), url)
+    }
+
+    /// Hands out the same job on every call.
+    struct Fixed(Ticker);
+
+    impl JobSource for Fixed {
+        fn next_job(&mut self) -> JobFuture<'_> {
+            Box::pin(async move { Ok(self.0.clone()) })
+        }
+    }
+
+    #[test]
+    fn take_event_keeps_characters_split_across_chunks() {
+        let mut stream = event_stream(String::new());
+        let data = "data: {\"ticker\":\"PEPÉ\"}\r\n\r\n".as_bytes();
+        // in the middle of `É`, then between `\r` and `\n`
+        let at = data.iter().position(|&byte| byte == 0xc3).unwrap() + 1;
+        let (first, rest) = data.split_at(at);
+        let (second, third) = rest.split_at(rest.len() - 1);
+
+        stream.buffer.extend_from_slice(first);
+        assert_eq!(stream.take_event(), None);
+        stream.buffer.extend_from_slice(second);
+        assert_eq!(stream.take_event(), None);
+        stream.buffer.extend_from_slice(third);
+        assert_eq!(
+            stream.take_event().as_deref(),
+            Some("{\"ticker\":\"PEPÉ\"}")
+        );
+        assert!(stream.buffer.is_empty());
+    }
+
+    #[tokio::test]
+    async fn event_stream_reassembles_chunks_and_skips_comments() {
+        let pepe = ticker("PEPÉ");
+        let doge = ticker("DOGE");
+        let first = event(&pepe);
+        // split inside the multi-byte `É`
+        let at = first.iter().position(|&byte| byte == 0xc3).unwrap() + 1;
+        let (url, _) = serve(vec![vec![
+            b": keep-alive\n\n".to_vec(),
+            first[..at].to_vec(),
+            first[at..].to_vec(),
+            b": keep-alive\r\n\r\n".to_vec(),
+            event(&doge),
+        ]])
+        .await;
+
+        let mut stream = event_stream(url);
+        assert_eq!(stream.next_job().await.unwrap().ticker, "PEPÉ");
+        assert_eq!(stream.next_job().await.unwrap().ticker, "DOGE");
+        assert!(stream.next_job().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn fallback_polls_while_push_is_down_then_resumes() {
+        let (url, mut connections) = serve(vec![
+            vec![event(&ticker("PUSHED"))],
+            vec![event(&ticker("RESUMED"))],
+        ])
+        .await;
+        let retry_after = Duration::from_millis(300);
+        let mut fallback = Fallback::new(
+            "PEPE".to_string(),
+            Box::new(event_stream(url)),
+            Box::new(Fixed(ticker("POLLED"))),
+            retry_after,
+        );
+
+        assert_eq!(fallback.next_job().await.unwrap().ticker, "PUSHED");
+        assert_eq!(connections.recv().await, Some(0));
+
+        // the stream closes: jobs are polled, and push isn't tried until `retry_after`
+        assert_eq!(fallback.next_job().await.unwrap().ticker, "POLLED");
+        assert_eq!(fallback.next_job().await.unwrap().ticker, "POLLED");
+        assert!(connections.try_recv().is_err());
+
+        tokio::time::sleep(retry_after).await;
+        assert_eq!(fallback.next_job().await.unwrap().ticker, "RESUMED");
+        assert_eq!(connections.recv().await, Some(1));
+        assert_eq!(fallback.polling_until, None);
+    }
+}
@@ -0,0 +1,99 @@
+use std::{
+    collections::{HashSet, VecDeque},
+    time::Instant,
+};
+
+/// Remembers the last `capacity` winning hashes so the same share is never
+/// submitted twice. A capacity of 0 turns deduplication off.
+#[derive(Debug)]
+pub struct Dedup {
+    capacity: usize,
+    seen: HashSet<String>,
+    order: VecDeque<String>,
+}
+
+impl Dedup {
+    pub fn new(capacity: usize) -> Dedup {
+        Dedup {
+            capacity,
+            seen: HashSet::new(),
+            order: VecDeque::new(),
+        }
+    }
+
+    /// Returns false if `hash` was already seen.
+    pub fn insert(&mut self, hash: &str) -> bool {
+        if self.capacity == 0 {
+            return true;
+        }
+
+        if !self.seen.insert(hash.to_string()) {
+            return false;
+        }
+
+        self.order.push_back(hash.to_string());
+        if self.order.len() > self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.seen.remove(&oldest);
+            }
+        }
+
+        true
+    }
+}
+
+/// Token bucket allowing `rate` submissions per second with bursts of up to `rate`,
+/// but at least 1 so rates below 1 still let a share through now and then. A rate of
+/// 0 means unlimited.
+#[derive(Debug)]
+pub struct RateLimiter {
+    rate: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(rate: f64) -> RateLimiter {
+        RateLimiter {
+            rate,
+            tokens: rate.max(1.0),
+            last: Instant::now(),
+        }
+    }
+
+    pub fn try_acquire(&mut self) -> bool {
+        if self.rate <= 0.0 {
+            return true;
+        }
+
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.rate.max(1.0));
+        self.last = now;
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn rate_below_one_lets_shares_through() {
+        let mut limiter = RateLimiter::new(0.5);
+        assert!(limiter.try_acquire());
+        assert!(!limiter.try_acquire());
+
+        // two seconds later a whole token has refilled
+        limiter.last -= Duration::from_secs(2);
+        assert!(limiter.try_acquire());
+        assert!(!limiter.try_acquire());
+    }
+}
@@ -0,0 +1,161 @@
+use super::*;
+use std::path::Path;
+
+/// Outcome of checking one nonce against a challenge.
+#[derive(Clone, Debug)]
+pub struct Verification {
+    pub hash: [u8; 32],
+    pub leading_zeros: u32,
+    pub difficulty: i32,
+    pub passed: bool,
+}
+
+/// Rebuilds the preimage the way the miner does, the reversed challenge bytes followed
+/// by the 8-byte nonce, and checks its `sha256d` against `difficulty`.
+pub fn verify_nonce(challenge: &str, nonce: &str, difficulty: i32) -> Result<Verification> {
+    let mut preimage = hex::decode(challenge)?;
+    preimage.reverse();
+
+    let nonce = hex::decode(nonce)?;
+    if nonce.len() != 8 {
+        anyhow::bail!("nonce must be 8 bytes, got {}", nonce.len());
+    }
+    preimage.extend_from_slice(&nonce);
+
+    let hash = Hash::sha256d(&preimage);
+
+    Ok(Verification {
+        hash,
+        leading_zeros: leading_zero_nibbles(&hash),
+        difficulty,
+        passed: meets_difficulty(&hash, &Target::new(difficulty)),
+    })
+}
+
+/// One line of a known-answer file: `<challenge> <nonce> <difficulty> [<hash>]`.
+#[derive(Clone, Debug)]
+pub struct KnownAnswer {
+    pub line: usize,
+    pub challenge: String,
+    pub nonce: String,
+    pub difficulty: i32,
+    pub hash: Option<String>,
+}
+
+impl KnownAnswer {
+    /// Reads a known-answer file. Blank lines and lines starting with `#` are skipped.
+    pub fn load(path: &Path) -> Result<Vec<KnownAnswer>> {
+        let mut vectors = vec![];
+
+        for (i, line) in std::fs::read_to_string(path)?.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let fields = line.split_whitespace().collect::<Vec<_>>();
+            let (challenge, nonce, difficulty, hash) = match fields[..] {
+                [challenge, nonce, difficulty] => (challenge, nonce, difficulty, None),
+                [challenge, nonce, difficulty, hash] => (challenge, nonce, difficulty, Some(hash)),
+                _ => anyhow::bail!(
+                    "{}:{}: expected `<challenge> <nonce> <difficulty> [<hash>]`",
+                    path.display(),
+                    i + 1
+                ),
+            };
+
+            vectors.push(KnownAnswer {
+                line: i + 1,
+                challenge: challenge.to_string(),
+                nonce: nonce.to_string(),
+                difficulty: difficulty
+                    .parse()
+                    .map_err(|e| anyhow::anyhow!("{}:{}: {}", path.display(), i + 1, e))?,
+                hash: hash.map(|hash| hash.to_lowercase()),
+            });
+        }
+
+        Ok(vectors)
+    }
+
+    /// Verifies the nonce and, when the file gave one, compares the expected hash.
+    pub fn check(&self) -> Result<(Verification, bool)> {
+        let verification = verify_nonce(&self.challenge, &self.nonce, self.difficulty)?;
+        let hash_matches = self
+            .hash
+            .as_ref()
+            .is_none_or(|hash| hash.eq_ignore_ascii_case(&hex::encode(verification.hash)));
+
+        Ok((verification, hash_matches))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CHALLENGE: &str = "7c411c8f00093de564fbc340abe4d03288a1720df53521d5b0e26c9521fb5fd9";
+    const NONCE: &str = "0000000000023b8a";
+    const HASH: &str = "00000a8af92d7cd17fe1e57579de608ae222f294b0b642b2fba9f91a2f35b428";
+
+    #[test]
+    fn verify_nonce_checks_the_difficulty() {
+        let verification = verify_nonce(CHALLENGE, NONCE, 5).unwrap();
+        assert_eq!(hex::encode(verification.hash), HASH);
+        assert_eq!(verification.leading_zeros, 5);
+        assert!(verification.passed);
+
+        assert!(!verify_nonce(CHALLENGE, NONCE, 6).unwrap().passed);
+        assert!(verify_nonce(CHALLENGE, "0000000000023b", 5).is_err());
+        assert!(verify_nonce("not hex", NONCE, 5).is_err());
+    }
+
+    #[test]
+    fn checked_in_known_answers_pass() {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("known-answers.txt");
+        let vectors = KnownAnswer::load(&path).unwrap();
+        assert!(!vectors.is_empty());
+
+        for vector in vectors {
+            let (verification, hash_matches) = vector.check().unwrap();
+            assert!(verification.passed, "line {}", vector.line);
+            assert!(hash_matches, "line {}", vector.line);
+        }
+    }
+
+    #[test]
+    fn load_skips_comments_and_blank_lines_and_check_reports_a_mismatch() {
+        let path = std::env::temp_dir().join(format!("pow20-vectors-{}.txt", std::process::id()));
+        let mismatch = HASH.replace("a8af", "a8ae");
+        std::fs::write(
+            &path,
+            format!(
+                "# challenge nonce difficulty hash\n\n  {} {} 5 {}\n{} {} 5\n",
+                CHALLENGE,
+                NONCE,
+                mismatch.to_uppercase(),
+                CHALLENGE,
+                NONCE
+            ),
+        )
+        .unwrap();
+        let vectors = KnownAnswer::load(&path).unwrap();
+
+        assert_eq!(vectors.len(), 2);
+        assert_eq!(vectors[0].line, 3);
+        assert_eq!(vectors[0].hash.as_deref(), Some(mismatch.as_str()));
+        let (verification, hash_matches) = vectors[0].check().unwrap();
+        assert!(verification.passed);
+        assert!(!hash_matches);
+
+        assert_eq!(vectors[1].line, 4);
+        assert_eq!(vectors[1].hash, None);
+        assert!(vectors[1].check().unwrap().1);
+
+        std::fs::write(&path, format!("\n{} {}\n", CHALLENGE, NONCE)).unwrap();
+        let e = KnownAnswer::load(&path).unwrap_err().to_string();
+        assert!(e.starts_with(&format!("{}:2:", path.display())), "{}", e);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}